@@ -2,6 +2,11 @@
 class AdminInterface {
     constructor() {
         this.apiBaseUrl = window.location.origin;
+        // The hat to control, from "?device=left", or the first one.
+        this.device = new URLSearchParams(window.location.search).get('device');
+        this.apiUrl = this.device
+            ? `${this.apiBaseUrl}/api/${encodeURIComponent(this.device)}`
+            : `${this.apiBaseUrl}/api`;
         this.authenticated = false;
         this.timerInterval = null;
         this.statsInterval = null;
@@ -56,6 +61,9 @@ class AdminInterface {
         document.getElementById('admin-panel').style.display = 'block';
         this.updateTimer();
         this.updateStats();
+        this.updateQueue();
+        this.updatePlaylist();
+        this.loadDevices();
         this.setupIconSelector();
     }
 
@@ -86,7 +94,7 @@ class AdminInterface {
 
     async loadIcons() {
         try {
-            const response = await fetch(`${this.apiBaseUrl}/api/get_icons`);
+            const response = await fetch(`${this.apiUrl}/get_icons`);
             const iconsText = await response.text();
 
             // Parse comma-separated icon names
@@ -140,7 +148,7 @@ class AdminInterface {
         try {
             const secret = sessionStorage.getItem('admin_secret');
 
-            const response = await fetch(`${this.apiBaseUrl}/api/admin`, {
+            const response = await fetch(`${this.apiUrl}/admin`, {
                 method: 'POST',
                 headers: {
                     'Content-Type': 'application/json',
@@ -170,7 +178,7 @@ class AdminInterface {
             const secret = sessionStorage.getItem('admin_secret');
             const seconds = minutes * 60; // Convert minutes to seconds
 
-            const response = await fetch(`${this.apiBaseUrl}/api/admin`, {
+            const response = await fetch(`${this.apiUrl}/admin`, {
                 method: 'POST',
                 headers: {
                     'Content-Type': 'application/json',
@@ -209,11 +217,76 @@ class AdminInterface {
         document.getElementById('custom-minutes').value = '';
     }
 
+    async setCountdownUntil() {
+        const time = document.getElementById('countdown-end').value;
+        if (!time) {
+            this.showErrorMessage('Please choose the end of the countdown');
+            return;
+        }
+
+        try {
+            // The browser reads the time in its own time zone
+            const response = await this.sendCommand({
+                CountdownUntil: { UnixMs: new Date(time).getTime() }
+            });
+            if (response.ok) {
+                this.updateTimer();
+                this.updateStatus();
+                this.showSuccessMessage(`Countdown to ${time.replace('T', ' ')} started`);
+            } else if (response.status !== 401) {
+                this.showErrorMessage('Invalid or past end time');
+            }
+        } catch (error) {
+            console.error('Error setting countdown:', error);
+            this.showErrorMessage('Failed to set countdown');
+        }
+    }
+
+    async timerCommand(command, message) {
+        try {
+            const response = await this.sendCommand(command);
+            if (response.ok) {
+                this.updateTimer();
+                this.updateStatus();
+                this.showSuccessMessage(message);
+            } else if (response.status !== 401) {
+                this.showErrorMessage('Failed to change the timer');
+            }
+        } catch (error) {
+            console.error('Error changing the timer:', error);
+            this.showErrorMessage('Failed to change the timer');
+        }
+    }
+
+    async setCountdownPhases(warnings) {
+        // Yellow under 5 minutes, red under 1 minute, blinking under 10 seconds
+        const phases = warnings ? [
+            { below_s: 300, background: '806000', digits: 'a0a0a0', effect: 'steady' },
+            { below_s: 60, background: '800000', digits: 'a0a0a0', effect: 'steady' },
+            { below_s: 10, background: '800000', digits: 'ffffff', effect: 'blink' },
+        ] : [];
+        const expiry = document.getElementById('countdown-expiry').value;
+
+        try {
+            const response = await this.sendCommand({
+                CountdownPhases: { phases: phases, expiry: expiry }
+            });
+            if (response.ok) {
+                this.showSuccessMessage(warnings ? 'Warning phases set' : 'Phases removed');
+            } else if (response.status !== 401) {
+                this.showErrorMessage('Failed to set the countdown phases');
+            }
+        } catch (error) {
+            console.error('Error setting countdown phases:', error);
+            this.showErrorMessage('Failed to set the countdown phases');
+        }
+    }
+
     async allowFunction() {
         try {
             const secret = sessionStorage.getItem('admin_secret');
 
-            const response = await fetch(`${this.apiBaseUrl}/api/admin`, {
+            const response = await fetch(`${this.apiUrl}/admin`, {
                 method: 'POST',
                 headers: {
                     'Content-Type': 'application/json',
@@ -240,7 +313,7 @@ class AdminInterface {
 
     async updateTimer() {
         try {
-            const response = await fetch(`${this.apiBaseUrl}/api/get_status`);
+            const response = await fetch(`${this.apiUrl}/get_status`);
             const status = await response.json();
 
             const timeRemaining = document.getElementById('time-remaining');
@@ -248,21 +321,32 @@ class AdminInterface {
             const timerDisplay = document.querySelector('.timer-display');
             const currentTimerSection = document.querySelector('.current-timer');
 
-            // Check if command is Countdown
-            if (status.command && status.command.Countdown !== undefined) {
-                // Countdown is active - show the section and value is in seconds
-                const totalSeconds = status.command.Countdown;
+            // Check if a countdown or stopwatch is shown
+            if (status.timer) {
+                // Timer is active - show the section and value is in seconds
+                const totalSeconds = status.timer.seconds;
+                const stopwatch = status.timer.mode === 'stopwatch';
                 const displayMinutes = Math.floor(totalSeconds / 60);
                 const displaySeconds = Math.floor(totalSeconds % 60);
+                // Like the hat, show hours if more than 99 minutes are left
+                const displayTime = displayMinutes > 99
+                    ? `${Math.floor(displayMinutes / 60)}:${(displayMinutes % 60).toString().padStart(2, '0')}:${displaySeconds.toString().padStart(2, '0')}`
+                    : `${displayMinutes.toString().padStart(2, '0')}:${displaySeconds.toString().padStart(2, '0')}`;
 
                 currentTimerSection.style.display = 'block';
-                timerStatus.textContent = 'Countdown Active';
+                timerStatus.textContent = stopwatch ? 'Stopwatch Active' : 'Countdown Active';
+                if (status.countdown_phase) {
+                    timerStatus.textContent += ` - under ${status.countdown_phase.below_s}s`;
+                }
+                if (status.timer.paused) {
+                    timerStatus.textContent += ' (paused)';
+                }
                 timerDisplay.classList.add('active');
                 timerDisplay.classList.remove('expired');
-                timeRemaining.textContent = `${displayMinutes.toString().padStart(2, '0')}:${displaySeconds.toString().padStart(2, '0')}`;
+                timeRemaining.textContent = displayTime;
 
                 // Check if expired
-                if (totalSeconds <= 0) {
+                if (!stopwatch && !status.timer.paused && totalSeconds <= 0) {
                     timerDisplay.classList.add('expired');
                     timerDisplay.classList.remove('active');
                     timerStatus.textContent = 'Countdown Expired';
@@ -276,21 +360,347 @@ class AdminInterface {
         }
     }
 
+    async sendCommand(command) {
+        const secret = sessionStorage.getItem('admin_secret');
+        const response = await fetch(`${this.apiUrl}/admin`, {
+            method: 'POST',
+            headers: {
+                'Content-Type': 'application/json',
+            },
+            body: JSON.stringify({
+                secret: secret,
+                command: command
+            })
+        });
+        if (response.status === 401) {
+            this.handleUnauthorized();
+        }
+        return response;
+    }
+
+    async updateQueue() {
+        try {
+            const response = await this.sendCommand('ListQueue');
+            if (!response.ok) {
+                return;
+            }
+            const listing = await response.json();
+
+            const current = document.getElementById('queue-current');
+            current.textContent = listing.current
+                ? `Showing: ${this.describeEntry(listing.current)}`
+                : 'No formula shown';
+
+            const list = document.getElementById('queue-list');
+            list.innerHTML = '';
+            listing.queue.forEach(entry => {
+                const item = document.createElement('li');
+                const text = document.createElement('span');
+                text.textContent = this.describeEntry(entry);
+                item.appendChild(text);
+
+                const front = document.createElement('button');
+                front.className = 'timer-btn';
+                front.textContent = 'Next';
+                front.onclick = () => this.queueCommand({ MoveFormulaToFront: entry.id }, 'Formula moved to front');
+                item.appendChild(front);
+
+                const remove = document.createElement('button');
+                remove.className = 'timer-btn reset';
+                remove.textContent = 'Remove';
+                remove.onclick = () => this.queueCommand({ RemoveFormula: entry.id }, 'Formula removed');
+                item.appendChild(remove);
+
+                list.appendChild(item);
+            });
+        } catch (error) {
+            console.error('Error updating queue:', error);
+        }
+    }
+
+    describeEntry(entry) {
+        const f = entry.formula;
+        const time = new Date(entry.submitted_ms).toLocaleTimeString();
+        return `#${entry.id} [${f.red}] [${f.green}] [${f.blue}] by ${entry.submitter} at ${time}`;
+    }
+
+    async queueCommand(command, message, notFound = 'Formula not in the queue anymore') {
+        try {
+            const response = await this.sendCommand(command);
+            if (response.ok) {
+                this.showSuccessMessage(message);
+            } else if (response.status === 404) {
+                this.showErrorMessage(notFound);
+            } else if (response.status !== 401) {
+                this.showErrorMessage('Failed to update the queue');
+            }
+            this.updateQueue();
+            this.updateStats();
+        } catch (error) {
+            console.error('Error updating queue:', error);
+            this.showErrorMessage('Failed to update the queue');
+        }
+    }
+
+    async setTransition() {
+        const kind = document.getElementById('transition-kind').value;
+        const duration = parseInt(document.getElementById('transition-duration').value);
+
+        if (isNaN(duration) || duration < 0 || duration > 10000) {
+            this.showErrorMessage('Please enter a duration between 0 and 10000 ms');
+            return;
+        }
+
+        try {
+            const response = await this.sendCommand({
+                Transition: { kind: kind, duration_ms: duration }
+            });
+            if (response.ok) {
+                this.showSuccessMessage(`${kind} transition set`);
+            } else if (response.status !== 401) {
+                this.showErrorMessage('Failed to set transition');
+            }
+        } catch (error) {
+            console.error('Error setting transition:', error);
+            this.showErrorMessage('Failed to set transition');
+        }
+    }
+
+    async uploadIcon() {
+        const name = document.getElementById('upload-icon-name').value.trim();
+        const file = document.getElementById('upload-icon-png').files[0];
+        const upload = { name: name };
+
+        if (!name) {
+            this.showErrorMessage('Please enter a name for the icon');
+            return;
+        }
+        if (file) {
+            const bytes = new Uint8Array(await file.arrayBuffer());
+            const format = file.type === 'image/gif' ? 'gif' : 'png';
+            upload[format] = btoa(String.fromCharCode(...bytes));
+        } else {
+            upload.pattern = document.getElementById('upload-icon-pattern').value;
+            upload.palette = document.getElementById('upload-icon-palette').value
+                .split(',')
+                .map(color => color.trim().replace('#', ''))
+                .filter(color => color);
+        }
+
+        const animation = document.getElementById('upload-icon-animation').value.trim();
+        if (animation) {
+            try {
+                upload.animation = JSON.parse(animation);
+            } catch (error) {
+                this.showErrorMessage('The animation is not valid JSON');
+                return;
+            }
+        }
+
+        try {
+            const response = await this.sendCommand({ UploadIcon: upload });
+            if (response.ok) {
+                await this.refreshIcons();
+                this.showSuccessMessage(`${name} icon uploaded`);
+            } else if (response.status !== 401) {
+                this.showErrorMessage('Invalid icon: check the name, the palette, and that it fits on the hat');
+            }
+        } catch (error) {
+            console.error('Error uploading icon:', error);
+            this.showErrorMessage('Failed to upload icon');
+        }
+    }
+
+    async removeSelectedIcon() {
+        const name = document.getElementById('icon-select')?.value;
+
+        if (!name) {
+            this.showErrorMessage('Please select an icon first');
+            return;
+        }
+
+        try {
+            const response = await this.sendCommand({ RemoveIcon: name });
+            if (response.ok) {
+                await this.refreshIcons();
+                this.showSuccessMessage(`${name} icon removed`);
+            } else if (response.status === 404) {
+                this.showErrorMessage('Only uploaded icons can be removed');
+            } else if (response.status !== 401) {
+                this.showErrorMessage('Failed to remove icon');
+            }
+        } catch (error) {
+            console.error('Error removing icon:', error);
+            this.showErrorMessage('Failed to remove icon');
+        }
+    }
+
+    async refreshIcons() {
+        await this.loadIcons();
+        this.setupIconSelector();
+    }
+
+    async showText() {
+        const message = document.getElementById('text-message').value;
+        const speed = parseFloat(document.getElementById('text-speed').value);
+        const color = document.getElementById('text-color').value.slice(1);
+
+        if (isNaN(speed) || Math.abs(speed) > 100) {
+            this.showErrorMessage('Please enter a speed between -100 and 100');
+            return;
+        }
+
+        try {
+            const response = await this.sendCommand({
+                Text: { message: message, speed: speed, color: color }
+            });
+            if (response.ok) {
+                this.updateStatus();
+                this.showSuccessMessage('Text displayed');
+            } else if (response.status !== 401) {
+                this.showErrorMessage('Failed to show text');
+            }
+        } catch (error) {
+            console.error('Error showing text:', error);
+            this.showErrorMessage('Failed to show text');
+        }
+    }
+
+    async uploadPlaylist() {
+        let playlist;
+        try {
+            playlist = JSON.parse(document.getElementById('playlist-json').value);
+        } catch (error) {
+            this.showErrorMessage('The playlist is not valid JSON');
+            return;
+        }
+        await this.playlistCommand({ UploadPlaylist: playlist }, 'Playlist uploaded');
+    }
+
+    async playlistCommand(command, message) {
+        try {
+            const response = await this.sendCommand(command);
+            if (response.ok) {
+                this.showSuccessMessage(message);
+            } else if (response.status !== 401) {
+                this.showErrorMessage('Playlist command failed');
+            }
+            this.updatePlaylist();
+            this.updateStatus();
+        } catch (error) {
+            console.error('Error sending playlist command:', error);
+            this.showErrorMessage('Playlist command failed');
+        }
+    }
+
+    async loadDevices() {
+        try {
+            const response = await fetch(`${this.apiBaseUrl}/api/get_devices`);
+            const devices = await response.json();
+            const current = this.device || devices[0];
+
+            const deviceSelect = document.getElementById('device-select');
+            const mirrorSelect = document.getElementById('mirror-select');
+            deviceSelect.innerHTML = '';
+            mirrorSelect.innerHTML = '';
+            devices.forEach(device => {
+                deviceSelect.add(new Option(device, device, false, device === current));
+                if (device !== current) {
+                    mirrorSelect.add(new Option(device, device));
+                }
+            });
+            this.otherDevices = devices.filter(device => device !== current);
+        } catch (error) {
+            console.error('Error loading devices:', error);
+        }
+    }
+
+    selectDevice() {
+        const device = document.getElementById('device-select').value;
+        window.location.search = `?device=${encodeURIComponent(device)}`;
+    }
+
+    async deviceCommand(command, message) {
+        try {
+            const response = await this.sendCommand(command);
+            if (response.ok) {
+                this.showSuccessMessage(message);
+            } else if (response.status !== 401) {
+                this.showErrorMessage('Device command failed');
+            }
+            this.updateStatus();
+        } catch (error) {
+            console.error('Error sending device command:', error);
+            this.showErrorMessage('Device command failed');
+        }
+    }
+
+    mirror() {
+        const source = document.getElementById('mirror-select').value;
+        if (!source) {
+            this.showErrorMessage('No other device to mirror');
+            return;
+        }
+        this.deviceCommand({ Mirror: source }, `Mirroring ${source}`);
+    }
+
+    syncDevices() {
+        this.deviceCommand({ Sync: this.otherDevices || [] }, 'Other devices synced');
+    }
+
+    async updatePlaylist() {
+        try {
+            const response = await this.sendCommand('PlaylistStatus');
+            if (!response.ok) {
+                return;
+            }
+            const status = await response.json();
+            const steps = status.playlist.steps.length;
+            let text = `Playlist ${status.state}, ${steps} steps`;
+            if (status.step !== null) {
+                text += `, step ${status.step + 1}`;
+            }
+            if (status.step_end_ms !== null) {
+                text += ` until ${new Date(status.step_end_ms).toLocaleTimeString()}`;
+            }
+            document.getElementById('playlist-status').textContent = text;
+        } catch (error) {
+            console.error('Error updating playlist:', error);
+        }
+    }
+
     async updateStats() {
         try {
-            const response = await fetch(`${this.apiBaseUrl}/api/get_status`);
+            const response = await fetch(`${this.apiUrl}/get_status`);
             const status = await response.json();
 
             // Update formulas queue from status
             document.getElementById('formulas-queue').textContent = status.formulas_queue || '0';
+            document.getElementById('telemetry').textContent = this.describeTelemetry(status.telemetry);
         } catch (error) {
             console.error('Error updating stats:', error);
         }
     }
 
+    describeTelemetry(telemetry) {
+        if (!telemetry || !telemetry.last_seen_ms) {
+            return 'No request yet';
+        }
+        const values = [
+            telemetry.co2 != null ? `CO2 ${telemetry.co2} ppm` : null,
+            telemetry.battery != null ? `battery ${telemetry.battery}%` : null,
+            telemetry.rssi != null ? `RSSI ${telemetry.rssi} dBm` : null,
+            telemetry.temperature != null ? `${telemetry.temperature.toFixed(1)} °C` : null,
+            telemetry.firmware ? `firmware ${telemetry.firmware}` : null,
+            telemetry.sequence != null ? `${telemetry.lost} lost` : null,
+        ].filter(value => value);
+        const seen = new Date(telemetry.last_seen_ms).toLocaleTimeString();
+        return `${values.join(', ')} (seen ${seen})`;
+    }
+
     async updateStatus() {
         try {
-            const response = await fetch(`${this.apiBaseUrl}/api/get_status`);
+            const response = await fetch(`${this.apiUrl}/get_status`);
             const status = await response.json();
 
             const statusText = document.getElementById('status-text');
@@ -301,8 +711,12 @@ class AdminInterface {
                 mode = 'Function Mode';
             } else if (status.command && status.command.Countdown !== undefined) {
                 mode = 'Countdown Mode';
+            } else if (status.command === 'Stopwatch') {
+                mode = 'Stopwatch Mode';
             } else if (status.command && status.command.Icon) {
                 mode = 'Icon Mode';
+            } else if (status.command && status.command.Text) {
+                mode = 'Text Mode';
             }
 
             statusText.textContent = `System Status: ${mode}`;
@@ -357,6 +771,8 @@ class AdminInterface {
             if (this.authenticated) {
                 this.updateTimer();
                 this.updateStats();
+                this.updateQueue();
+                this.updatePlaylist();
             }
             // Always update status (visible before auth)
             this.updateStatus();
@@ -379,6 +795,31 @@ function setCustomCountdown() {
     adminInterface.setCustomCountdown();
 }
 
+function setCountdownUntil() {
+    adminInterface.setCountdownUntil();
+}
+
+function startStopwatch() {
+    adminInterface.timerCommand('Stopwatch', 'Stopwatch started');
+}
+
+function pauseTimer() {
+    adminInterface.timerCommand('PauseTimer', 'Timer paused');
+}
+
+function resumeTimer() {
+    adminInterface.timerCommand('ResumeTimer', 'Timer resumed');
+}
+
+function addTime(seconds) {
+    const sign = seconds < 0 ? '-' : '+';
+    adminInterface.timerCommand({ AddTime: seconds }, `${sign}${Math.abs(seconds) / 60} min`);
+}
+
+function setCountdownPhases(warnings) {
+    adminInterface.setCountdownPhases(warnings);
+}
+
 function allowFunction() {
     adminInterface.allowFunction();
 }
@@ -387,6 +828,58 @@ function showSelectedIcon() {
     adminInterface.showSelectedIcon();
 }
 
+function uploadIcon() {
+    adminInterface.uploadIcon();
+}
+
+function removeSelectedIcon() {
+    adminInterface.removeSelectedIcon();
+}
+
+function showText() {
+    adminInterface.showText();
+}
+
+function setTransition() {
+    adminInterface.setTransition();
+}
+
+function uploadPlaylist() {
+    adminInterface.uploadPlaylist();
+}
+
+function startPlaylist() {
+    adminInterface.playlistCommand('StartPlaylist', 'Playlist started');
+}
+
+function pausePlaylist() {
+    adminInterface.playlistCommand('PausePlaylist', 'Playlist paused');
+}
+
+function skipFormula() {
+    adminInterface.queueCommand('SkipFormula', 'Skipped current formula', 'No formula waiting to skip to');
+}
+
+function clearQueue() {
+    adminInterface.queueCommand('ClearQueue', 'Queue cleared');
+}
+
+function selectDevice() {
+    adminInterface.selectDevice();
+}
+
+function mirrorDevice() {
+    adminInterface.mirror();
+}
+
+function stopMirror() {
+    adminInterface.deviceCommand({ Mirror: null }, 'Mirroring stopped');
+}
+
+function syncDevices() {
+    adminInterface.syncDevices();
+}
+
 // Initialize when DOM is loaded
 document.addEventListener('DOMContentLoaded', () => {
     adminInterface = new AdminInterface();
@@ -0,0 +1,76 @@
+// Colour models for LED Hat formulas.
+// This mirrors ColorModel, OutputMapping, and LED::from_hsv / LED::from_hsl /
+// LED::map_output in src/hat/leds.rs, so the simulation shows the same colours
+// as the hat.
+const ColorModel = {
+  models: ["rgb", "hsv", "hsl"],
+
+  // Same as OutputMapping::default(), until the server sends its own.
+  defaultMapping: { input_min: -1, input_max: 1, max_value: 63, gamma: 1 },
+
+  // Maps a formula value to the 0..1 range of the colour models.
+  toUnit(mapping, value) {
+    return (
+      (value - mapping.input_min) / (mapping.input_max - mapping.input_min)
+    );
+  },
+
+  // Applies the gamma curve and the maximum value of the mapping to
+  // [red, green, blue] in 0..255, like the hat does.
+  // The result is scaled back to 0..255 to be shown on the screen.
+  mapOutput(mapping, rgb) {
+    return rgb.map((c) => {
+      if (mapping.max_value === 0) {
+        return 0;
+      }
+      const v = Math.floor(
+        Math.pow(c / 256, mapping.gamma) * (mapping.max_value + 1),
+      );
+      return Math.round(
+        (Math.min(v, mapping.max_value) * 255) / mapping.max_value,
+      );
+    });
+  },
+
+  // Converts three values between 0 and 1 to [red, green, blue] in 0..255.
+  // The hue wraps around, all other values are clamped.
+  toRGB(model, a, b, c) {
+    switch (model) {
+      case "hsv": {
+        const s = this.clamp(b);
+        const v = this.clamp(c);
+        return this.hueRGB(a).map((h) => this.toByte(v * (1 - s + s * h)));
+      }
+      case "hsl": {
+        const s = this.clamp(b);
+        const l = this.clamp(c);
+        const chroma = (1 - Math.abs(2 * l - 1)) * s;
+        return this.hueRGB(a).map((h) => this.toByte(l + chroma * (h - 0.5)));
+      }
+      default:
+        return [a, b, c].map((v) => this.toByte(v));
+    }
+  },
+
+  // The fully saturated colour of this hue, with 0 being red, 1/3 green
+  // and 2/3 blue.
+  hueRGB(hue) {
+    const h = (hue - Math.floor(hue)) * 6;
+    return [
+      this.clamp(Math.abs(h - 3) - 1),
+      this.clamp(2 - Math.abs(h - 2)),
+      this.clamp(2 - Math.abs(h - 4)),
+    ];
+  },
+
+  clamp(v) {
+    return Math.min(Math.max(v, 0), 1);
+  },
+
+  toByte(v) {
+    if (isNaN(v)) {
+      return 0;
+    }
+    return Math.floor(Math.min(Math.max(v * 256, 0), 255));
+  },
+};
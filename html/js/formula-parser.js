@@ -12,7 +12,38 @@ class FormulaParser {
       sqrt: Math.sqrt,
       exp: Math.exp,
       abs: Math.abs,
+      neg: (a) => -a,
+      floor: Math.floor,
+      ceil: Math.ceil,
+      fract: (a) => a - Math.floor(a),
+      sign: Math.sign,
+      ln: Math.log,
       pow: (a, b) => Math.pow(a, b),
+      min: Math.min,
+      max: Math.max,
+      step: (edge, a) => (a < edge ? 0 : 1),
+      hypot: Math.hypot,
+      atan2: (a, b) => Math.atan2(a, b) / Math.PI,
+      "<": (a, b) => (a < b ? 1 : 0),
+      ">": (a, b) => (a > b ? 1 : 0),
+      "<=": (a, b) => (a <= b ? 1 : 0),
+      ">=": (a, b) => (a >= b ? 1 : 0),
+      "==": (a, b) => (a === b ? 1 : 0),
+      and: (a, b) => (a !== 0 && b !== 0 ? 1 : 0),
+      or: (a, b) => (a !== 0 || b !== 0 ? 1 : 0),
+      not: (a) => (a === 0 ? 1 : 0),
+      "?": (cond, a, b) => (cond !== 0 ? a : b),
+      clamp: (a, lo, hi) => Math.min(Math.max(a, lo), hi),
+      mix: (a, b, r) => a + (b - a) * r,
+      smoothstep: (e0, e1, a) => {
+        const v =
+          e0 === e1
+            ? a < e0
+              ? 0
+              : 1
+            : Math.min(Math.max((a - e0) / (e1 - e0), 0), 1);
+        return v * v * (3 - 2 * v);
+      },
       "+": (a, b) => a + b,
       "-": (a, b) => a - b,
       "*": (a, b) => a * b,
@@ -22,7 +53,39 @@ class FormulaParser {
     };
 
     // Binary operators (require two operands)
-    this.binaryOperators = ["+", "-", "*", "/", "%", "^", "pow"];
+    this.binaryOperators = [
+      "+",
+      "-",
+      "*",
+      "/",
+      "%",
+      "^",
+      "pow",
+      "min",
+      "max",
+      "step",
+      "hypot",
+      "atan2",
+      "<",
+      ">",
+      "<=",
+      ">=",
+      "==",
+      "and",
+      "or",
+    ];
+
+    // Forth-style stack words, as [operands, function returning the new values]
+    this.stackWords = {
+      dup: [1, (a) => [a, a]],
+      swap: [2, (a, b) => [b, a]],
+      drop: [1, () => []],
+      over: [2, (a, b) => [a, b, a]],
+      rot: [3, (a, b, c) => [b, c, a]],
+    };
+
+    // Ternary functions (require three operands)
+    this.ternaryFunctions = ["?", "clamp", "mix", "smoothstep"];
 
     // Unary functions (require one operand)
     this.unaryFunctions = [
@@ -35,6 +98,13 @@ class FormulaParser {
       "sqrt",
       "exp",
       "abs",
+      "neg",
+      "floor",
+      "ceil",
+      "fract",
+      "sign",
+      "ln",
+      "not",
     ];
   }
 
@@ -51,6 +121,8 @@ class FormulaParser {
       for (let token of tokens) {
         if (this.isNumber(token)) {
           stack.push(parseFloat(token));
+        } else if (this.stackWords.hasOwnProperty(token)) {
+          this.applyStackWord(stack, token);
         } else if (variables.hasOwnProperty(token)) {
           stack.push(variables[token]);
         } else if (this.functions.hasOwnProperty(token)) {
@@ -69,6 +141,16 @@ class FormulaParser {
             }
             const a = stack.pop();
             stack.push(this.functions[token](a));
+          } else if (this.ternaryFunctions.includes(token)) {
+            if (stack.length < 3) {
+              throw new Error(
+                `Ternary function '${token}' requires three operands`,
+              );
+            }
+            const c = stack.pop();
+            const b = stack.pop();
+            const a = stack.pop();
+            stack.push(this.functions[token](a, b, c));
           } else {
             throw new Error(`Unknown function: ${token}`);
           }
@@ -92,6 +174,15 @@ class FormulaParser {
     }
   }
 
+  // Applies a stack word like 'dup' to the stack
+  applyStackWord(stack, token) {
+    const [operands, word] = this.stackWords[token];
+    if (stack.length < operands) {
+      throw new Error(`Stack word '${token}' requires ${operands} operands`);
+    }
+    stack.push(...word(...stack.splice(-operands, operands)));
+  }
+
   // Check if a token is a number
   isNumber(token) {
     return !isNaN(parseFloat(token)) && isFinite(parseFloat(token));
@@ -114,6 +205,14 @@ class FormulaParser {
         stack.push("number");
       } else if (["x", "y", "t"].includes(token)) {
         stack.push("variable");
+      } else if (this.stackWords.hasOwnProperty(token)) {
+        if (stack.length < this.stackWords[token][0]) {
+          errors.push(
+            `Token ${i + 1} ('${token}'): Stack word requires ${this.stackWords[token][0]} operands`,
+          );
+        } else {
+          this.applyStackWord(stack, token);
+        }
       } else if (this.functions.hasOwnProperty(token)) {
         if (this.binaryOperators.includes(token)) {
           if (stack.length < 2) {
@@ -134,6 +233,14 @@ class FormulaParser {
             stack.pop();
             stack.push("result");
           }
+        } else if (this.ternaryFunctions.includes(token)) {
+          if (stack.length < 3) {
+            errors.push(
+              `Token ${i + 1} ('${token}'): Ternary function requires three operands`,
+            );
+          } else {
+            stack.splice(-3, 3, "result");
+          }
         }
       } else {
         errors.push(`Token ${i + 1} ('${token}'): Unknown token`);
@@ -162,6 +269,8 @@ class FormulaParser {
       for (let token of tokens) {
         if (this.isNumber(token) || ["x", "y", "t"].includes(token)) {
           stack.push(token);
+        } else if (this.stackWords.hasOwnProperty(token)) {
+          this.applyStackWord(stack, token);
         } else if (this.functions.hasOwnProperty(token)) {
           if (this.binaryOperators.includes(token)) {
             if (stack.length < 2) {
@@ -169,8 +278,8 @@ class FormulaParser {
             }
             const b = stack.pop();
             const a = stack.pop();
-            if (token === "pow") {
-              stack.push(`pow(${a}, ${b})`);
+            if (/^[a-z]/.test(token) && !["and", "or"].includes(token)) {
+              stack.push(`${token}(${a}, ${b})`);
             } else {
               stack.push(`(${a} ${token} ${b})`);
             }
@@ -180,6 +289,16 @@ class FormulaParser {
             }
             const a = stack.pop();
             stack.push(`${token}(${a})`);
+          } else if (this.ternaryFunctions.includes(token)) {
+            if (stack.length < 3) {
+              return `Error: ${token} needs three operands`;
+            }
+            const args = stack.splice(-3, 3);
+            if (token === "?") {
+              stack.push(`(${args[0]} ? ${args[1]} : ${args[2]})`);
+            } else {
+              stack.push(`${token}(${args.join(", ")})`);
+            }
           }
         } else {
           // Unknown token - stop here
@@ -198,6 +317,8 @@ class FormulaParser {
     return {
       unary: this.unaryFunctions,
       binary: this.binaryOperators,
+      ternary: this.ternaryFunctions,
+      stack: Object.keys(this.stackWords),
       variables: ["x", "y", "t"],
     };
   }
@@ -8,6 +8,7 @@ class LEDSimulation {
     this.isPlaying = false;
     this.currentTime = 0;
     this.formulaParser = new FormulaParser();
+    this.outputMapping = ColorModel.defaultMapping;
 
     this.initializeLEDs();
 
@@ -69,9 +70,19 @@ class LEDSimulation {
         t: this.currentTime,
       };
 
-      const red = this.formulaParser.evaluateForLED(redFormula, variables);
-      const green = this.formulaParser.evaluateForLED(greenFormula, variables);
-      const blue = this.formulaParser.evaluateForLED(blueFormula, variables);
+      const model = document.getElementById("color-model")?.value || "rgb";
+      const [red, green, blue] = ColorModel.mapOutput(
+        this.outputMapping,
+        ColorModel.toRGB(
+          model,
+          ...[redFormula, greenFormula, blueFormula].map((formula) =>
+            ColorModel.toUnit(
+              this.outputMapping,
+              this.formulaParser.evaluate(formula, variables),
+            ),
+          ),
+        ),
+      );
 
       led.element.style.backgroundColor = `rgb(${red}, ${green}, ${blue})`;
       led.element.style.boxShadow = `0 0 10px rgba(${red}, ${green}, ${blue}, 0.5)`;
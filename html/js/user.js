@@ -2,6 +2,11 @@
 class UserInterface {
   constructor() {
     this.apiBaseUrl = window.location.origin;
+    // The hat to control, from "?device=left", or the first one.
+    this.device = new URLSearchParams(window.location.search).get("device");
+    this.apiUrl = this.device
+      ? `${this.apiBaseUrl}/api/${encodeURIComponent(this.device)}`
+      : `${this.apiBaseUrl}/api`;
     this.ledSimulation = null;
     this.formulaParser = new FormulaParser();
     this.checkInterval = null;
@@ -348,7 +353,7 @@ class UserInterface {
 
   async checkBackendConnectivity() {
     try {
-      const response = await fetch(`${this.apiBaseUrl}/api/get_status`, {
+      const response = await fetch(`${this.apiUrl}/get_status`, {
         method: "GET",
         headers: {
           "Content-Type": "application/json",
@@ -387,6 +392,11 @@ class UserInterface {
       return;
     }
 
+    // Mirror the output mapping of the hat in the simulation
+    if (status.output_mapping && this.ledSimulation) {
+      this.ledSimulation.outputMapping = status.output_mapping;
+    }
+
     // Check if allowed to send formulas using the allow_function field
     const isReady = status.allow_function === true;
 
@@ -405,8 +415,12 @@ class UserInterface {
         const minutes = Math.floor(totalSeconds / 60);
         const seconds = Math.floor(totalSeconds % 60);
         accessText.textContent = `Hat is in countdown mode (${minutes}:${seconds.toString().padStart(2, '0')} remaining)`;
+      } else if (status.command === "Stopwatch") {
+        accessText.textContent = "Hat is showing a stopwatch";
       } else if (status.command && status.command.Icon) {
         accessText.textContent = `Hat is showing an icon (${status.command.Icon})`;
+      } else if (status.command && status.command.Text) {
+        accessText.textContent = "Hat is showing a message";
       } else {
         accessText.textContent = "Hat is busy";
       }
@@ -444,7 +458,7 @@ class UserInterface {
     }
 
     try {
-      const response = await fetch(`${this.apiBaseUrl}/api/set_formulas`, {
+      const response = await fetch(`${this.apiUrl}/set_formulas`, {
         method: "POST",
         headers: {
           "Content-Type": "application/json",
@@ -453,11 +467,25 @@ class UserInterface {
           red: redFormula,
           green: greenFormula,
           blue: blueFormula,
+          color: document.getElementById("color-model")?.value || "rgb",
         }),
       });
 
       if (response.ok) {
         this.showStatusMessage("Formulas sent to hat successfully!", "success");
+      } else if (response.status === 429) {
+        this.showStatusMessage(
+          "You already have formulas waiting - try again once they were shown",
+          "error",
+        );
+      } else if (response.status === 422) {
+        const error = await response.json();
+        const where =
+          error.index !== undefined ? ` at token ${error.index + 1}` : "";
+        this.showStatusMessage(
+          `Invalid ${error.channel} formula: ${error.kind.replaceAll("_", " ")}${where}`,
+          "error",
+        );
       } else {
         const errorText = await response.text();
         this.showStatusMessage(`Failed to send to hat: ${errorText}`, "error");
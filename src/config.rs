@@ -0,0 +1,375 @@
+use std::{
+    collections::HashSet,
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use clap::Parser;
+use serde::Deserialize;
+
+use crate::hat::geometry::{Direction, Geometry, LayoutKind};
+
+/// The file read if no other is given with `--config`.
+const CONFIG_DEFAULT: &str = "ledhat.toml";
+/// The device used when none are configured.
+const DEVICE_DEFAULT: &str = "hat";
+/// Maximum length of the id of a device.
+const DEVICE_ID_MAX: usize = 32;
+/// Names of the API routes, which cannot be device ids, as the routes of
+/// the first device have no id.
+const DEVICE_ID_RESERVED: [&str; 8] = [
+    "admin",
+    "get_devices",
+    "get_icons",
+    "get_leds",
+    "get_status",
+    "history",
+    "replay",
+    "set_formulas",
+];
+
+/// Settings of the server, read from a TOML file, where every value can be
+/// overridden on the command line.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub geometry: Geometry,
+    #[serde(default = "Config::default_http")]
+    pub http: SocketAddr,
+    #[serde(default = "Config::default_udp")]
+    pub udp: SocketAddr,
+    // An empty secret disables the admin interface.
+    #[serde(default)]
+    pub admin_secret: String,
+    #[serde(default = "Config::default_history")]
+    pub history: PathBuf,
+    #[serde(default = "Config::default_icons")]
+    pub icons: PathBuf,
+    // Reverse proxies whose X-Forwarded-For header gives the address of
+    // the client.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+    // Maximum number of formulas one client can have in the queue.
+    #[serde(default = "Config::default_queue_limit")]
+    pub queue_limit: usize,
+    // Hats driven by this server, or a single one with the geometry above.
+    #[serde(default)]
+    pub devices: Vec<DeviceConfig>,
+}
+
+/// One of the hats driven by the server, identified by the id it sends
+/// in its UDP requests.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    pub id: String,
+    // Defaults to the geometry of the configuration.
+    #[serde(default)]
+    pub geometry: Option<Geometry>,
+    // Default to the files of the configuration, with the id appended.
+    #[serde(default)]
+    pub history: Option<PathBuf>,
+    #[serde(default)]
+    pub icons: Option<PathBuf>,
+}
+
+impl DeviceConfig {
+    fn is_valid_id(id: &str) -> bool {
+        !id.is_empty()
+            && id.len() <= DEVICE_ID_MAX
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            && !DEVICE_ID_RESERVED.contains(&id)
+    }
+}
+
+// Like "history.jsonl" becoming "history-left.jsonl" for the device "left".
+fn with_id(path: &Path, id: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{stem}-{id}.{}", ext.to_string_lossy()),
+        None => format!("{stem}-{id}"),
+    };
+    path.with_file_name(name)
+}
+
+#[derive(Debug, Parser)]
+#[command(about = "Serves the pictures of the LED hat")]
+struct Args {
+    /// TOML file with the configuration [default: ledhat.toml, if it exists]
+    #[arg(long, short, env = "LEDHAT_CONFIG")]
+    config: Option<PathBuf>,
+    /// Arrangement of the LEDs: spiral, matrix or ring
+    #[arg(long, value_parser = parse_layout)]
+    layout: Option<LayoutKind>,
+    /// Number of LEDs of the hat
+    #[arg(long)]
+    leds: Option<usize>,
+    /// Number of LEDs in one turn of the spiral, or in one row of a matrix
+    #[arg(long)]
+    circumference: Option<usize>,
+    /// LED of the spiral where the strip is connected
+    #[arg(long)]
+    start: Option<usize>,
+    /// Direction of the spiral: clockwise or counterclockwise
+    #[arg(long, value_parser = parse_direction)]
+    direction: Option<Direction>,
+    /// Whether every row is shifted by half an LED
+    #[arg(long)]
+    interleaved: Option<bool>,
+    /// Whether every second row of a matrix is wired the other way
+    #[arg(long)]
+    serpentine: Option<bool>,
+    /// Address of the web server
+    #[arg(long)]
+    http: Option<SocketAddr>,
+    /// Address where the hat fetches its LEDs
+    #[arg(long)]
+    udp: Option<SocketAddr>,
+    /// Secret of the admin interface, which is disabled without one
+    #[arg(long, env = "LEDHAT_ADMIN")]
+    admin_secret: Option<String>,
+    /// JSONL file with all formulas ever sent
+    #[arg(long, env = "LEDHAT_HISTORY")]
+    history: Option<PathBuf>,
+    /// JSON file with the icons uploaded by the admin
+    #[arg(long, env = "LEDHAT_ICONS")]
+    icons: Option<PathBuf>,
+    /// Address of a reverse proxy, whose X-Forwarded-For header is trusted
+    #[arg(
+        long = "trusted-proxy",
+        env = "LEDHAT_TRUSTED_PROXIES",
+        value_delimiter = ','
+    )]
+    trusted_proxies: Vec<IpAddr>,
+    /// Maximum number of formulas one client can have in the queue
+    #[arg(long)]
+    queue_limit: Option<usize>,
+}
+
+fn parse_direction(s: &str) -> Result<Direction, String> {
+    match s {
+        "clockwise" => Ok(Direction::Clockwise),
+        "counterclockwise" => Ok(Direction::CounterClockwise),
+        _ => Err("must be clockwise or counterclockwise".into()),
+    }
+}
+
+fn parse_layout(s: &str) -> Result<LayoutKind, String> {
+    match s {
+        "spiral" => Ok(LayoutKind::Spiral),
+        "matrix" => Ok(LayoutKind::Matrix),
+        "ring" => Ok(LayoutKind::Ring),
+        _ => Err("must be spiral, matrix or ring".into()),
+    }
+}
+
+impl Config {
+    fn default_http() -> SocketAddr {
+        ([0, 0, 0, 0], 8080).into()
+    }
+
+    fn default_udp() -> SocketAddr {
+        ([0, 0, 0, 0], 8081).into()
+    }
+
+    fn default_history() -> PathBuf {
+        "history.jsonl".into()
+    }
+
+    fn default_icons() -> PathBuf {
+        "icons.json".into()
+    }
+
+    fn default_queue_limit() -> usize {
+        3
+    }
+
+    /// Reads the configuration from the command line and the config file.
+    /// Returns a message for the user if the file cannot be read, or the
+    /// geometry of the hat doesn't make sense.
+    pub fn load() -> Result<Config, String> {
+        let args = Args::parse();
+        let path = args.config.clone().unwrap_or(CONFIG_DEFAULT.into());
+        let (content, source) = match fs::read_to_string(&path) {
+            Ok(content) => (content, Some(path)),
+            // Only the default config file is optional.
+            Err(_) if args.config.is_none() => (String::new(), None),
+            Err(e) => return Err(format!("cannot read {}: {e}", path.display())),
+        };
+        Self::parse(&content, args).map_err(|e| match source {
+            Some(path) => format!("{}: {e}", path.display()),
+            None => e,
+        })
+    }
+
+    fn parse(content: &str, args: Args) -> Result<Config, String> {
+        let mut config: Config =
+            toml::from_str(content).map_err(|e| format!("invalid configuration: {e}"))?;
+        let geometry = &mut config.geometry;
+        geometry.layout = args.layout.unwrap_or(geometry.layout);
+        geometry.leds = args.leds.unwrap_or(geometry.leds);
+        geometry.circumference = args.circumference.unwrap_or(geometry.circumference);
+        geometry.start = args.start.unwrap_or(geometry.start);
+        geometry.direction = args.direction.unwrap_or(geometry.direction);
+        geometry.interleaved = args.interleaved.unwrap_or(geometry.interleaved);
+        geometry.serpentine = args.serpentine.unwrap_or(geometry.serpentine);
+        config.http = args.http.unwrap_or(config.http);
+        config.udp = args.udp.unwrap_or(config.udp);
+        config.admin_secret = args.admin_secret.unwrap_or(config.admin_secret);
+        config.history = args.history.unwrap_or(config.history);
+        config.icons = args.icons.unwrap_or(config.icons);
+        if !args.trusted_proxies.is_empty() {
+            config.trusted_proxies = args.trusted_proxies;
+        }
+        config.queue_limit = args.queue_limit.unwrap_or(config.queue_limit);
+        if config.queue_limit == 0 {
+            return Err("invalid queue_limit: must be at least 1".into());
+        }
+        config
+            .geometry
+            .validate()
+            .map_err(|e| format!("invalid geometry: {e}"))?;
+        config.devices = config.complete_devices()?;
+        Ok(config)
+    }
+
+    // Fills in the geometry and files of every device, or adds the single
+    // default device.
+    fn complete_devices(&self) -> Result<Vec<DeviceConfig>, String> {
+        if self.devices.is_empty() {
+            return Ok(vec![DeviceConfig {
+                id: DEVICE_DEFAULT.into(),
+                geometry: Some(self.geometry),
+                history: Some(self.history.clone()),
+                icons: Some(self.icons.clone()),
+            }]);
+        }
+        let mut ids = HashSet::new();
+        self.devices
+            .iter()
+            .map(|device| {
+                let id = &device.id;
+                if !DeviceConfig::is_valid_id(id) {
+                    return Err(format!("invalid device id {id:?}"));
+                }
+                if !ids.insert(id) {
+                    return Err(format!("device {id} is configured twice"));
+                }
+                let geometry = device.geometry.unwrap_or(self.geometry);
+                geometry
+                    .validate()
+                    .map_err(|e| format!("invalid geometry of device {id}: {e}"))?;
+                Ok(DeviceConfig {
+                    id: id.clone(),
+                    geometry: Some(geometry),
+                    history: Some(
+                        device
+                            .history
+                            .clone()
+                            .unwrap_or_else(|| with_id(&self.history, id)),
+                    ),
+                    icons: Some(
+                        device
+                            .icons
+                            .clone()
+                            .unwrap_or_else(|| with_id(&self.icons, id)),
+                    ),
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(content: &str, args: &[&str]) -> Result<Config, String> {
+        let args = Args::try_parse_from(["led_hat"].iter().chain(args)).unwrap();
+        Config::parse(content, args)
+    }
+
+    #[test]
+    fn test_config() {
+        let config = parse("", &[]).unwrap();
+        assert_eq!(config.geometry, Geometry::new(300, 37));
+        assert_eq!(config.http, Config::default_http());
+
+        let file = r#"
+            admin_secret = "hat"
+            udp = "127.0.0.1:9000"
+            [geometry]
+            leds = 100
+            circumference = 10
+            direction = "counterclockwise"
+        "#;
+        let config = parse(file, &["--leds", "200", "--admin-secret", "cli"]).unwrap();
+        assert_eq!(config.geometry.leds, 200);
+        assert_eq!(config.geometry.direction, Direction::CounterClockwise);
+        assert_eq!(config.udp, "127.0.0.1:9000".parse().unwrap());
+        assert_eq!(config.admin_secret, "cli");
+
+        // Errors from the file and in the geometry
+        assert!(parse("color = 1", &[]).is_err());
+        assert!(parse("[geometry]\nleds = 100", &[]).is_err());
+        let err = parse("", &["--circumference", "400"]).unwrap_err();
+        assert!(err.contains("circumference"), "{err}");
+
+        let config = parse("", &["--layout", "ring", "--leds", "24"]).unwrap();
+        assert_eq!(config.geometry.layout, LayoutKind::Ring);
+        let file = "[geometry]\nlayout = \"matrix\"\nleds = 64\ncircumference = 8";
+        let config = parse(file, &["--serpentine", "true"]).unwrap();
+        assert_eq!(config.geometry.layout().size(), (8, 8));
+        assert!(config.geometry.serpentine);
+        assert!(Args::try_parse_from(["led_hat", "--layout", "square"]).is_err());
+
+        assert_eq!(config.queue_limit, 3);
+        assert!(config.trusted_proxies.is_empty());
+        let file = "queue_limit = 5\ntrusted_proxies = [\"10.0.0.1\"]";
+        let config = parse(file, &["--trusted-proxy", "10.0.0.2,::1"]).unwrap();
+        assert_eq!(config.queue_limit, 5);
+        assert_eq!(
+            config.trusted_proxies,
+            [
+                "10.0.0.2".parse::<IpAddr>().unwrap(),
+                "::1".parse().unwrap()
+            ]
+        );
+        assert!(parse("", &["--queue-limit", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_devices() {
+        let config = parse("", &["--leds", "200"]).unwrap();
+        assert_eq!(config.devices.len(), 1);
+        assert_eq!(config.devices[0].id, "hat");
+        assert_eq!(config.devices[0].geometry.unwrap().leds, 200);
+        assert_eq!(config.devices[0].history, Some("history.jsonl".into()));
+
+        let file = r#"
+            [[devices]]
+            id = "left"
+            [[devices]]
+            id = "ring"
+            icons = "ring.json"
+            geometry = { layout = "ring", leds = 24 }
+        "#;
+        let config = parse(file, &[]).unwrap();
+        let ids: Vec<_> = config.devices.iter().map(|d| d.id.as_str()).collect();
+        assert_eq!(ids, ["left", "ring"]);
+        assert_eq!(config.devices[0].geometry, Some(Geometry::new(300, 37)));
+        assert_eq!(config.devices[0].history, Some("history-left.jsonl".into()));
+        assert_eq!(config.devices[1].icons, Some("ring.json".into()));
+
+        let twice = "[[devices]]\nid = \"a\"\n[[devices]]\nid = \"a\"";
+        assert!(parse(twice, &[]).is_err());
+        assert!(parse("[[devices]]\nid = \"admin\"", &[]).is_err());
+        assert!(parse("[[devices]]\nid = \"a/b\"", &[]).is_err());
+        let err = parse("[[devices]]\nid = \"a\"\ngeometry = { leds = 0 }", &[]).unwrap_err();
+        assert!(err.contains("device a"), "{err}");
+    }
+}
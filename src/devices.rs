@@ -0,0 +1,235 @@
+use std::sync::Arc;
+
+use axum::http::StatusCode;
+use tokio::sync::Mutex;
+
+use crate::config::DeviceConfig;
+use crate::hat::{
+    geometry::Geometry, history::History, icon::IconType, leds::LED, registry::IconRegistry,
+    switch::Switch,
+};
+
+pub type SharedHat = Arc<Mutex<Switch>>;
+
+/// All hats driven by the server, each with its own mode, formulas and
+/// icons.
+/// The first device is also used by the requests which don't name one.
+pub struct Devices {
+    devices: Vec<(String, SharedHat)>,
+}
+
+impl Devices {
+    /// Every hat lets one client queue up to `queue_limit` formulas.
+    pub fn new(configs: &[DeviceConfig], queue_limit: usize) -> Self {
+        let devices = configs
+            .iter()
+            .map(|config| {
+                let geometry = config.geometry.unwrap_or_default();
+                tracing::info!("Device {}: {geometry:?}", config.id);
+                let mut hat = Switch::new(
+                    geometry,
+                    config
+                        .history
+                        .clone()
+                        .map(History::open)
+                        .unwrap_or_default(),
+                    config
+                        .icons
+                        .clone()
+                        .map(IconRegistry::open)
+                        .unwrap_or_default(),
+                    queue_limit,
+                );
+                hat.show_icon(IconType::Fosdem);
+                (config.id.clone(), Arc::new(Mutex::new(hat)))
+            })
+            .collect();
+        Self { devices }
+    }
+
+    pub fn get(&self, id: &str) -> Option<SharedHat> {
+        self.devices
+            .iter()
+            .find(|(device, _)| device == id)
+            .map(|(_, hat)| hat.clone())
+    }
+
+    pub fn first(&self) -> (&str, SharedHat) {
+        let (id, hat) = &self.devices[0];
+        (id, hat.clone())
+    }
+
+    pub fn ids(&self) -> Vec<String> {
+        self.devices.iter().map(|(id, _)| id.clone()).collect()
+    }
+
+    /// The LEDs of the hat, or of the device it mirrors, and the geometry
+    /// of the hat to send them.
+    pub async fn get_leds(&self, hat: &SharedHat) -> (Geometry, Vec<LED>) {
+        let (geometry, mirror) = {
+            let mut hat = hat.lock().await;
+            match hat.mirror().and_then(|id| self.get(id)) {
+                Some(source) => (hat.geometry(), source),
+                None => return (hat.geometry(), hat.get_leds()),
+            }
+        };
+        let leds = mirror.lock().await.get_leds();
+        (geometry, leds)
+    }
+
+    /// Shows the LEDs of the source on the hat, or its own LEDs again
+    /// without a source.
+    /// Mirroring only works between hats with the same layout, and
+    /// not through another mirror, so a mirrored hat cannot mirror another one.
+    pub async fn mirror(&self, id: &str, source: Option<String>) -> StatusCode {
+        let position = |id: &str| self.devices.iter().position(|(device, _)| device == id);
+        let Some(index) = position(id) else {
+            return StatusCode::NOT_FOUND;
+        };
+        let Some(source_id) = source else {
+            self.devices[index].1.lock().await.set_mirror(None);
+            return StatusCode::OK;
+        };
+        let Some(source) = position(&source_id) else {
+            return StatusCode::NOT_FOUND;
+        };
+        if source == index {
+            return StatusCode::BAD_REQUEST;
+        }
+        // All devices are locked, always in the same order, so that two
+        // mirrors set at the same time cannot both pass the checks.
+        let mut hats = vec![];
+        for (_, hat) in &self.devices {
+            hats.push(hat.lock().await);
+        }
+        let source_layout = hats[source].geometry().layout();
+        let layout = hats[index].geometry().layout();
+        if hats[source].mirror().is_some()
+            || hats.iter().any(|other| other.mirror() == Some(id))
+            || source_layout.len() != layout.len()
+            || source_layout.size() != layout.size()
+        {
+            return StatusCode::BAD_REQUEST;
+        }
+        hats[index].set_mirror(Some(source_id));
+        StatusCode::OK
+    }
+
+    /// Shows the scene of the hat on the other devices.
+    /// Nothing changes if one of them is unknown, or doesn't have the
+    /// icon shown.
+    pub async fn sync(&self, id: &str, targets: &[String]) -> StatusCode {
+        let Some(hat) = self.get(id) else {
+            return StatusCode::NOT_FOUND;
+        };
+        let scene = hat.lock().await.scene();
+        let mut hats = vec![];
+        for target in targets.iter().filter(|target| *target != id) {
+            let Some(hat) = self.get(target) else {
+                return StatusCode::NOT_FOUND;
+            };
+            if !hat.lock().await.icon_exists(scene.icon()) {
+                return StatusCode::BAD_REQUEST;
+            }
+            hats.push(hat);
+        }
+        for hat in hats {
+            hat.lock().await.sync(scene.clone());
+        }
+        StatusCode::OK
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hat::geometry::LayoutKind;
+
+    fn device(id: &str, geometry: Geometry) -> DeviceConfig {
+        DeviceConfig {
+            id: id.into(),
+            geometry: Some(geometry),
+            history: None,
+            icons: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mirror() {
+        let ring = Geometry {
+            layout: LayoutKind::Ring,
+            ..Geometry::new(24, 0)
+        };
+        let devices = Devices::new(
+            &[
+                device("left", Geometry::new(300, 37)),
+                device("right", Geometry::new(300, 37)),
+                device("band", ring),
+            ],
+            3,
+        );
+        assert_eq!(devices.ids(), ["left", "right", "band"]);
+        assert_eq!(devices.first().0, "left");
+
+        assert_eq!(
+            devices.mirror("right", Some("left".into())).await,
+            StatusCode::OK
+        );
+        let right = devices.get("right").unwrap();
+        assert_eq!(devices.get_leds(&right).await.1.len(), 300);
+        // Not through another mirror, nor onto another layout
+        assert_eq!(
+            devices.mirror("left", Some("right".into())).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            devices.mirror("band", Some("left".into())).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            devices.mirror("left", Some("nope".into())).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(devices.mirror("right", None).await, StatusCode::OK);
+        assert_eq!(right.lock().await.mirror(), None);
+
+        // Only one of two opposite mirrors set at the same time
+        let (left_right, right_left) = tokio::join!(
+            devices.mirror("left", Some("right".into())),
+            devices.mirror("right", Some("left".into())),
+        );
+        assert_eq!(
+            [left_right, right_left]
+                .iter()
+                .filter(|status| **status == StatusCode::OK)
+                .count(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync() {
+        let devices = Devices::new(
+            &[
+                device("left", Geometry::new(300, 37)),
+                device("right", Geometry::new(300, 37)),
+            ],
+            3,
+        );
+        let left = devices.get("left").unwrap();
+        left.lock().await.start_stopwatch();
+        let targets = ["right".to_string()];
+        assert_eq!(devices.sync("left", &targets).await, StatusCode::OK);
+        let right = devices.get("right").unwrap();
+        let timer = |status| serde_json::to_value(status).unwrap()["timer"].clone();
+        assert_eq!(timer(right.lock().await.get_status())["mode"], "stopwatch");
+        assert_eq!(
+            timer(right.lock().await.get_status()),
+            timer(left.lock().await.get_status())
+        );
+        assert_eq!(
+            devices.sync("left", &["nope".into()]).await,
+            StatusCode::NOT_FOUND
+        );
+    }
+}
@@ -0,0 +1,474 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Number of frames kept for every hat, which it can acknowledge to get
+/// the next frame as a delta.
+pub const FRAMES_KEPT: usize = 8;
+/// Number of hats asking for the same device whose frames are kept.
+pub const HATS_PER_DEVICE: usize = 4;
+
+/// How the RGB bytes of the LEDs are encoded in a response.
+/// The hat sends the formats it understands as a bit mask, with bit n for
+/// the format n, and gets the shortest of them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PixelFormat {
+    // Three bytes per LED, red, green, and blue.
+    Rgb = 0,
+    // Six bits per channel, packed from the highest bit, so four channels
+    // take three bytes.
+    // Only used if no channel is above 63, like for the formulas.
+    Rgb6 = 1,
+    // The number of colours minus one, the colours as RGB, and then the
+    // index of every LED with 1, 2, 4, or 8 bits, packed from the highest
+    // bit.
+    Palette = 2,
+    // Runs of LEDs of the same colour, each as its length from 1 to 255,
+    // followed by the colour as RGB.
+    Rle = 3,
+    // The sequence number of the frame it is based on, then the LEDs as
+    // the number of unchanged LEDs, the number of changed LEDs, both up to
+    // 255, followed by the colours of the changed LEDs as RGB.
+    Delta = 4,
+}
+
+impl PixelFormat {
+    pub const ALL: [PixelFormat; 5] = [
+        PixelFormat::Rgb,
+        PixelFormat::Rgb6,
+        PixelFormat::Palette,
+        PixelFormat::Rle,
+        PixelFormat::Delta,
+    ];
+
+    /// The bit of the format in the mask sent by the hats.
+    pub fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// The frames last sent to a hat, by their sequence number.
+#[derive(Debug, Default)]
+pub struct SentFrames {
+    frames: VecDeque<(u32, Vec<u8>)>,
+    // When the last frame was sent, in ms since the epoch.
+    last_ms: u128,
+}
+
+impl SentFrames {
+    pub fn get(&self, sequence: u32) -> Option<&[u8]> {
+        self.frames
+            .iter()
+            .rev()
+            .find(|(s, _)| *s == sequence)
+            .map(|(_, pixels)| pixels.as_slice())
+    }
+
+    /// Keeps the frame sent for the sequence number.
+    /// A repeated sequence number replaces the frame sent before, and an
+    /// older one, from a restarted hat, drops all frames, so an ack always
+    /// refers to the last frame sent with its number.
+    pub fn push(&mut self, sequence: u32, pixels: Vec<u8>) {
+        if let Some((last, _)) = self.frames.back() {
+            if sequence.wrapping_sub(*last) >= u32::MAX / 2 {
+                self.frames.clear();
+            }
+        }
+        self.frames.retain(|(s, _)| *s != sequence);
+        if self.frames.len() == FRAMES_KEPT {
+            self.frames.pop_front();
+        }
+        self.frames.push_back((sequence, pixels));
+    }
+}
+
+/// The frames sent to every hat which accepts deltas, by its device and
+/// address.
+/// Only the hats which asked last are kept, as the address of a hat can
+/// change, and anybody can send requests.
+#[derive(Debug)]
+pub struct SentByHat {
+    hats: HashMap<(String, SocketAddr), SentFrames>,
+    max: usize,
+}
+
+impl SentByHat {
+    /// Keeps the frames of up to [HATS_PER_DEVICE] hats per device.
+    pub fn new(devices: usize) -> Self {
+        Self {
+            hats: HashMap::new(),
+            max: devices * HATS_PER_DEVICE,
+        }
+    }
+
+    pub fn get(&self, device: &str, addr: SocketAddr) -> Option<&SentFrames> {
+        self.hats.get(&(device.to_string(), addr))
+    }
+
+    /// Keeps the frame sent to the hat, forgetting the hat which asked
+    /// least recently if there are too many.
+    pub fn push(
+        &mut self,
+        device: &str,
+        addr: SocketAddr,
+        sequence: u32,
+        pixels: Vec<u8>,
+        time_ms: u128,
+    ) {
+        let key = (device.to_string(), addr);
+        if !self.hats.contains_key(&key) && self.hats.len() >= self.max {
+            let oldest = self
+                .hats
+                .iter()
+                .min_by_key(|(_, frames)| frames.last_ms)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.hats.remove(&oldest);
+            }
+        }
+        let frames = self.hats.entry(key).or_default();
+        frames.push(sequence, pixels);
+        frames.last_ms = time_ms;
+    }
+}
+
+/// Encodes the RGB bytes in the shortest of the formats in the mask, or
+/// as RGB if none is better.
+/// The base is the frame acknowledged by the hat, and its sequence
+/// number, for a delta.
+pub fn encode_best(
+    formats: u8,
+    pixels: &[u8],
+    base: Option<(u32, &[u8])>,
+) -> (PixelFormat, Vec<u8>) {
+    let (format, payload) = PixelFormat::ALL
+        .into_iter()
+        .filter(|format| formats & format.bit() != 0)
+        .filter_map(|format| Some((format, encode(format, pixels, base)?)))
+        .min_by_key(|(_, payload)| payload.len())
+        .unwrap_or_else(|| (PixelFormat::Rgb, pixels.to_vec()));
+    debug_assert_eq!(
+        decode(format, pixels.len() / 3, &payload, base).as_deref(),
+        Some(pixels),
+        "{format:?} doesn't decode to the frame"
+    );
+    (format, payload)
+}
+
+/// Encodes the RGB bytes of the LEDs, or returns None if the format cannot
+/// hold them.
+pub fn encode(format: PixelFormat, pixels: &[u8], base: Option<(u32, &[u8])>) -> Option<Vec<u8>> {
+    match format {
+        PixelFormat::Rgb => Some(pixels.to_vec()),
+        PixelFormat::Rgb6 => {
+            if pixels.iter().any(|&c| c > 63) {
+                return None;
+            }
+            Some(pack(pixels.iter().copied(), 6))
+        }
+        PixelFormat::Palette => {
+            let mut palette: Vec<&[u8]> = vec![];
+            let mut indexes = vec![];
+            for led in pixels.chunks(3) {
+                let index = match palette.iter().position(|&c| c == led) {
+                    Some(index) => index,
+                    None if palette.len() < 256 => {
+                        palette.push(led);
+                        palette.len() - 1
+                    }
+                    None => return None,
+                };
+                indexes.push(index as u8);
+            }
+            let mut buf = vec![palette.len().saturating_sub(1) as u8];
+            buf.extend(palette.concat());
+            buf.extend(pack(indexes.into_iter(), index_bits(palette.len())));
+            Some(buf)
+        }
+        PixelFormat::Rle => {
+            let mut buf = vec![];
+            for led in pixels.chunks(3) {
+                match buf.len().checked_sub(4) {
+                    Some(run) if buf[run] < 255 && &buf[run + 1..] == led => buf[run] += 1,
+                    _ => {
+                        buf.push(1);
+                        buf.extend_from_slice(led);
+                    }
+                }
+            }
+            Some(buf)
+        }
+        PixelFormat::Delta => {
+            let (sequence, base) = base.filter(|(_, base)| base.len() == pixels.len())?;
+            let mut buf = sequence.to_le_bytes().to_vec();
+            let leds: Vec<_> = pixels.chunks(3).zip(base.chunks(3)).collect();
+            let mut i = 0;
+            while i < leds.len() {
+                let skip = leds[i..]
+                    .iter()
+                    .take(255)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                i += skip;
+                let changed = leds[i..]
+                    .iter()
+                    .take(255)
+                    .take_while(|(a, b)| a != b)
+                    .count();
+                buf.extend_from_slice(&[skip as u8, changed as u8]);
+                buf.extend(leds[i..i + changed].iter().flat_map(|(led, _)| led.iter()));
+                i += changed;
+            }
+            Some(buf)
+        }
+    }
+}
+
+/// The reference decoder, as implemented by the hats: returns the RGB
+/// bytes of the given number of LEDs, or None if the payload doesn't
+/// match.
+/// A delta needs the frame it is based on, and its sequence number.
+pub fn decode(
+    format: PixelFormat,
+    leds: usize,
+    payload: &[u8],
+    base: Option<(u32, &[u8])>,
+) -> Option<Vec<u8>> {
+    let len = leds * 3;
+    let pixels = match format {
+        PixelFormat::Rgb => payload.to_vec(),
+        PixelFormat::Rgb6 => unpack(payload, 6, len)?,
+        PixelFormat::Palette => {
+            let (&count, rest) = payload.split_first()?;
+            let count = count as usize + 1;
+            let (palette, indexes) = rest.split_at_checked(count * 3)?;
+            unpack(indexes, index_bits(count), leds)?
+                .into_iter()
+                .map(|i| palette.get(i as usize * 3..i as usize * 3 + 3))
+                .collect::<Option<Vec<_>>>()?
+                .concat()
+        }
+        PixelFormat::Rle => {
+            let mut pixels = vec![];
+            for run in payload.chunks(4) {
+                let [count, rgb @ ..] = run else {
+                    return None;
+                };
+                if rgb.len() != 3 {
+                    return None;
+                }
+                for _ in 0..*count {
+                    pixels.extend_from_slice(rgb);
+                }
+            }
+            pixels
+        }
+        PixelFormat::Delta => {
+            let (sequence, base) = base?;
+            let (header, mut rest) = payload.split_at_checked(4)?;
+            if LittleEndian::read_u32(header) != sequence || base.len() != len {
+                return None;
+            }
+            let mut pixels = vec![];
+            while let [skip, changed, tail @ ..] = rest {
+                let start = pixels.len();
+                pixels.extend_from_slice(base.get(start..start + *skip as usize * 3)?);
+                let (colors, tail) = tail.split_at_checked(*changed as usize * 3)?;
+                pixels.extend_from_slice(colors);
+                rest = tail;
+            }
+            if !rest.is_empty() {
+                return None;
+            }
+            pixels
+        }
+    };
+    (pixels.len() == len).then_some(pixels)
+}
+
+// The bits needed for the indexes of a palette with this many colours.
+fn index_bits(colors: usize) -> u8 {
+    match colors {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8,
+    }
+}
+
+// Packs the lowest bits of the values, from their highest bit.
+fn pack(values: impl Iterator<Item = u8>, bits: u8) -> Vec<u8> {
+    let mut buf = vec![];
+    let mut used = 0;
+    for value in values {
+        for bit in (0..bits).rev() {
+            if used % 8 == 0 {
+                buf.push(0);
+            }
+            if (value >> bit) & 1 == 1 {
+                *buf.last_mut().unwrap() |= 0x80 >> (used % 8);
+            }
+            used += 1;
+        }
+    }
+    buf
+}
+
+fn unpack(buf: &[u8], bits: u8, count: usize) -> Option<Vec<u8>> {
+    if buf.len() != (count * bits as usize).div_ceil(8) {
+        return None;
+    }
+    Some(
+        (0..count * bits as usize)
+            .map(|i| (buf[i / 8] >> (7 - i % 8)) & 1)
+            .collect::<Vec<_>>()
+            .chunks(bits as usize)
+            .map(|b| b.iter().fold(0, |value, bit| value << 1 | bit))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn roundtrip(format: PixelFormat, pixels: &[u8], base: Option<(u32, &[u8])>) -> usize {
+        let payload = encode(format, pixels, base).unwrap();
+        assert_eq!(
+            decode(format, pixels.len() / 3, &payload, base).as_deref(),
+            Some(pixels),
+            "{format:?}"
+        );
+        payload.len()
+    }
+
+    // An icon: black with a few coloured LEDs.
+    fn icon() -> Vec<u8> {
+        let mut pixels = vec![0; 900];
+        for i in (30..60).chain(200..210) {
+            pixels[i * 3..i * 3 + 3].copy_from_slice(&[0xa0, 0x40, 0]);
+        }
+        pixels
+    }
+
+    #[test]
+    fn test_formats() {
+        let icon = icon();
+        assert_eq!(roundtrip(PixelFormat::Rgb, &icon, None), 900);
+        assert_eq!(roundtrip(PixelFormat::Palette, &icon, None), 1 + 6 + 38);
+        assert_eq!(roundtrip(PixelFormat::Rle, &icon, None), 5 * 4);
+        assert_eq!(encode(PixelFormat::Rgb6, &icon, None), None);
+        assert_eq!(encode(PixelFormat::Delta, &icon, None), None);
+
+        // Like a formula, with all channels up to 63
+        let formula: Vec<u8> = (0..900).map(|i| (i * 7 % 64) as u8).collect();
+        assert_eq!(roundtrip(PixelFormat::Rgb6, &formula, None), 675);
+        roundtrip(PixelFormat::Rle, &formula, None);
+        roundtrip(PixelFormat::Palette, &formula, None);
+        let colorful: Vec<u8> = (0..300)
+            .flat_map(|i| [i as u8, (i / 256) as u8, 0])
+            .collect();
+        assert_eq!(encode(PixelFormat::Palette, &colorful, None), None);
+
+        // Long runs and many changes
+        let white = vec![255; 900];
+        assert_eq!(roundtrip(PixelFormat::Rle, &white, None), 8);
+        assert_eq!(roundtrip(PixelFormat::Palette, &white, None), 4 + 38);
+        let mut next = icon.clone();
+        next[3] = 1;
+        assert_eq!(
+            roundtrip(PixelFormat::Delta, &next, Some((7, &icon))),
+            4 + 2 + 3 + 4
+        );
+        roundtrip(PixelFormat::Delta, &formula, Some((7, &icon)));
+        roundtrip(PixelFormat::Delta, &icon, Some((7, &white)));
+    }
+
+    #[test]
+    fn test_decode() {
+        let icon = icon();
+        let rle = encode(PixelFormat::Rle, &icon, None).unwrap();
+        assert_eq!(decode(PixelFormat::Rle, 299, &rle, None), None);
+        assert_eq!(decode(PixelFormat::Rle, 300, &rle[..19], None), None);
+        let delta = encode(PixelFormat::Delta, &icon, Some((7, &icon))).unwrap();
+        assert_eq!(
+            decode(PixelFormat::Delta, 300, &delta, Some((8, &icon))),
+            None
+        );
+        assert_eq!(decode(PixelFormat::Delta, 300, &delta, None), None);
+        let palette = encode(PixelFormat::Palette, &icon, None).unwrap();
+        assert_eq!(
+            decode(PixelFormat::Palette, 300, &palette[..40], None),
+            None
+        );
+    }
+
+    #[test]
+    fn test_best() {
+        let icon = icon();
+        let all = PixelFormat::ALL.iter().fold(0, |mask, f| mask | f.bit());
+        assert_eq!(encode_best(0, &icon, None).0, PixelFormat::Rgb);
+        assert_eq!(encode_best(all, &icon, None).0, PixelFormat::Rle);
+        assert_eq!(
+            encode_best(all, &icon, Some((1, &icon))),
+            (PixelFormat::Delta, vec![1, 0, 0, 0, 255, 0, 45, 0])
+        );
+        let formula: Vec<u8> = (0..900).map(|i| (i * 7 % 64) as u8).collect();
+        let mask = PixelFormat::Rgb6.bit() | PixelFormat::Rle.bit();
+        assert_eq!(encode_best(mask, &formula, None).0, PixelFormat::Rgb6);
+
+        let mut sent = SentFrames::default();
+        for sequence in 0..10 {
+            sent.push(sequence, vec![sequence as u8]);
+        }
+        assert_eq!(sent.get(1), None);
+        assert_eq!(sent.get(9), Some([9].as_slice()));
+    }
+
+    #[test]
+    fn test_sent_frames() {
+        let mut sent = SentFrames::default();
+        assert_eq!(sent.get(0), None);
+        sent.push(u32::MAX, vec![1]);
+        sent.push(0, vec![2]);
+        assert_eq!(sent.get(u32::MAX), Some([1].as_slice()));
+
+        // A retry replaces the frame
+        sent.push(0, vec![3]);
+        assert_eq!(sent.get(0), Some([3].as_slice()));
+        assert_eq!(sent.get(u32::MAX), Some([1].as_slice()));
+
+        // A restarted hat starts again
+        sent.push(1, vec![4]);
+        sent.push(0, vec![5]);
+        assert_eq!(sent.get(0), Some([5].as_slice()));
+        assert_eq!(sent.get(1), None);
+        assert_eq!(sent.get(u32::MAX), None);
+    }
+
+    #[test]
+    fn test_sent_by_hat() {
+        let mut sent = SentByHat::new(1);
+        let addr = |port| SocketAddr::from(([10, 0, 0, 2], port));
+        for port in 0..HATS_PER_DEVICE as u16 {
+            sent.push("hat", addr(port), 1, vec![port as u8], port as u128);
+        }
+        // The hat which asked first is forgotten
+        sent.push("hat", addr(1), 2, vec![1], 10);
+        sent.push("hat", addr(100), 1, vec![100], 11);
+        assert_eq!(sent.hats.len(), HATS_PER_DEVICE);
+        assert!(sent.get("hat", addr(0)).is_none());
+        assert_eq!(
+            sent.get("hat", addr(1)).unwrap().get(2),
+            Some([1].as_slice())
+        );
+        assert_eq!(
+            sent.get("hat", addr(100)).unwrap().get(1),
+            Some([100].as_slice())
+        );
+        assert!(sent.get("other", addr(1)).is_none());
+    }
+}
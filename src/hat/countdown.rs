@@ -1,92 +1,452 @@
 use std::f32::consts::PI;
 
-use crate::hat::leds::{LEDCriss, LED};
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::hat::{
+    geometry::Geometry,
+    leds::{LEDCriss, LED},
+};
+
+/// The end of a countdown as a wall-clock time, so it doesn't depend on
+/// when the admin sent the command.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum CountdownEnd {
+    // ms since the epoch
+    UnixMs(u128),
+    // Like "2026-02-01T14:00:00+01:00"
+    Rfc3339(String),
+}
+
+impl CountdownEnd {
+    /// Returns the end in ms since the epoch, or None if the time cannot be
+    /// parsed.
+    pub fn to_ms(&self) -> Option<u128> {
+        let date = match self {
+            CountdownEnd::UnixMs(ms) => return Some(*ms),
+            CountdownEnd::Rfc3339(time) => OffsetDateTime::parse(time, &Rfc3339).ok()?,
+        };
+        u128::try_from(date.unix_timestamp_nanos() / 1_000_000).ok()
+    }
+}
+
+/// Maximum number of phases of a countdown.
+pub const PHASES_MAX: usize = 16;
+
+/// How the digits and background of a phase are animated.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Effect {
+    #[default]
+    Steady,
+    // On and off every half second.
+    Blink,
+    // Fades in and out every two seconds.
+    Pulse,
+}
+
+impl Effect {
+    fn brightness(self, now_ms: u128) -> f32 {
+        match self {
+            Effect::Steady => 1.,
+            Effect::Blink if now_ms % 1000 < 500 => 1.,
+            Effect::Blink => 0.,
+            Effect::Pulse => ((now_ms % 2000) as f32 / 1000. * PI).sin() * 0.5 + 0.5,
+        }
+    }
+}
+
+/// How the countdown looks while less than `below_s` seconds are left.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct Phase {
+    pub below_s: u128,
+    pub background: LED,
+    pub digits: LED,
+    #[serde(default)]
+    pub effect: Effect,
+}
+
+impl Phase {
+    // The look of the countdown while no phase applies.
+    fn normal() -> Self {
+        Self {
+            below_s: u128::MAX,
+            background: LED::from_rgb(0, 0x60, 0),
+            digits: LED::from_rgb(0xa0, 0xa0, 0xa0),
+            effect: Effect::Steady,
+        }
+    }
+}
+
+/// What is shown once the countdown is over.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Expiry {
+    // All LEDs slowly pulsing in pink.
+    #[default]
+    Pulse,
+    // All LEDs blinking in red.
+    Blink,
+    // A rainbow turning around the hat.
+    Rainbow,
+    // 00:00 blinking in the colours of the last phase.
+    Zeros,
+}
+
+/// The phases of the countdown, in any order, and the animation once the
+/// time is over.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct CountdownPhases {
+    pub phases: Vec<Phase>,
+    #[serde(default)]
+    pub expiry: Expiry,
+}
+
+impl CountdownPhases {
+    pub fn is_valid(&self) -> bool {
+        self.phases.len() <= PHASES_MAX && self.phases.iter().all(|p| p.below_s > 0)
+    }
+}
+
+/// Whether the timer counts down to an end, or up from a start.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimerMode {
+    Countdown,
+    Stopwatch,
+}
+
+/// The timer as shown in the status.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct TimerStatus {
+    pub mode: TimerMode,
+    pub paused: bool,
+    // Seconds left for a countdown, or elapsed for a stopwatch.
+    pub seconds: u128,
+}
+
+/// The timer and its phases, without the LEDs, to show the same timer on
+/// another hat.
+#[derive(Debug, Clone)]
+pub struct CountdownState {
+    mode: TimerMode,
+    time_ms: u128,
+    paused: bool,
+    phases: CountdownPhases,
+}
+
+// Digits are drawn with a fixed width, so they don't move while counting.
+const DIGITS_WIDTH: usize = 7;
 
 pub struct Countdown {
-    end_ms: u128,
+    mode: TimerMode,
+    // While running, the end of the countdown or the start of the
+    // stopwatch, in ms.
+    // While paused, the ms left or elapsed.
+    time_ms: u128,
+    paused: bool,
     leds: LEDCriss,
+    // Sorted by below_s, so the first matching phase is the shortest.
+    phases: CountdownPhases,
 }
 
 impl Countdown {
-    pub fn new(total: usize, circum: usize) -> Self {
+    pub fn new(geometry: Geometry) -> Self {
         Self {
-            end_ms: 0,
-            leds: LEDCriss::new(total, circum),
+            mode: TimerMode::Countdown,
+            time_ms: 0,
+            paused: false,
+            leds: LEDCriss::new(geometry),
+            phases: CountdownPhases::default(),
+        }
+    }
+
+    pub fn state(&self) -> CountdownState {
+        CountdownState {
+            mode: self.mode,
+            time_ms: self.time_ms,
+            paused: self.paused,
+            phases: self.phases.clone(),
         }
     }
 
+    pub fn set_state(&mut self, state: CountdownState) {
+        self.mode = state.mode;
+        self.time_ms = state.time_ms;
+        self.paused = state.paused;
+        self.phases = state.phases;
+    }
+
     pub fn set_countdown(&mut self, end_ms: u128) {
-        self.end_ms = end_ms;
+        self.mode = TimerMode::Countdown;
+        self.time_ms = end_ms;
+        self.paused = false;
+    }
+
+    /// Counts up from zero.
+    pub fn start_stopwatch(&mut self, now_ms: u128) {
+        self.mode = TimerMode::Stopwatch;
+        self.time_ms = now_ms;
+        self.paused = false;
+    }
+
+    /// Freezes the time left or elapsed.
+    pub fn pause(&mut self, now_ms: u128) {
+        if !self.paused {
+            self.time_ms = self.value_ms(now_ms);
+            self.paused = true;
+        }
+    }
+
+    pub fn resume(&mut self, now_ms: u128) {
+        if self.paused {
+            self.paused = false;
+            self.set_value_ms(self.time_ms, now_ms);
+        }
+    }
+
+    /// Adds time left to a countdown, or elapsed to a stopwatch.
+    /// Negative seconds remove time, down to zero.
+    pub fn add_time(&mut self, seconds: i64, now_ms: u128) {
+        let delta = seconds.unsigned_abs() as u128 * 1000;
+        let value = match seconds < 0 {
+            true => self.value_ms(now_ms).saturating_sub(delta),
+            false => self.value_ms(now_ms) + delta,
+        };
+        self.set_value_ms(value, now_ms);
+    }
+
+    pub fn get_status(&self, now_ms: u128) -> TimerStatus {
+        TimerStatus {
+            mode: self.mode,
+            paused: self.paused,
+            seconds: self.value_ms(now_ms) / 1000,
+        }
+    }
+
+    // The ms left for a countdown, or elapsed for a stopwatch.
+    fn value_ms(&self, now_ms: u128) -> u128 {
+        match (self.paused, self.mode) {
+            (true, _) => self.time_ms,
+            (false, TimerMode::Countdown) => self.time_ms.saturating_sub(now_ms),
+            (false, TimerMode::Stopwatch) => now_ms.saturating_sub(self.time_ms),
+        }
+    }
+
+    fn set_value_ms(&mut self, value_ms: u128, now_ms: u128) {
+        self.time_ms = match (self.paused, self.mode) {
+            (true, _) => value_ms,
+            (false, TimerMode::Countdown) => now_ms + value_ms,
+            (false, TimerMode::Stopwatch) => now_ms.saturating_sub(value_ms),
+        };
+    }
+
+    fn expired(&self, now_ms: u128) -> bool {
+        self.mode == TimerMode::Countdown && !self.paused && now_ms > self.time_ms
+    }
+
+    pub fn set_phases(&mut self, mut phases: CountdownPhases) {
+        phases.phases.sort_by_key(|p| p.below_s);
+        self.phases = phases;
+    }
+
+    /// Returns the phase shown, or None if no phase applies or the time
+    /// is over.
+    pub fn get_phase(&self, now_ms: u128) -> Option<Phase> {
+        if self.mode == TimerMode::Stopwatch || self.expired(now_ms) {
+            return None;
+        }
+        let index = self.phase_index(self.value_ms(now_ms) / 1000)?;
+        Some(self.phases.phases[index])
+    }
+
+    fn phase_index(&self, left: u128) -> Option<usize> {
+        self.phases.phases.iter().position(|p| left < p.below_s)
+    }
+
+    fn phase(&self, left: u128) -> Phase {
+        self.phase_index(left)
+            .map(|i| self.phases.phases[i])
+            .unwrap_or_else(Phase::normal)
     }
 
     pub fn get_leds(&mut self, now_ms: u128) -> Vec<super::LED> {
-        if now_ms > self.end_ms {
-            let red = LED::from_rgb(0xff, 0x80, 0x80)
-                .brightness(((now_ms % 2000) as f32 / 1000. * PI).sin() * 0.5 + 0.5);
-            self.leds.fill(&red);
+        if self.expired(now_ms) {
+            self.draw_expiry(now_ms);
         } else {
-            let left = (self.end_ms - now_ms) / 1000;
-            self.leds.fill(&LED::from_rgb(0, 0x60, 0));
-            let left_m = format!("{:02}", left / 60);
-            let left_s = format!("{:02}", left % 60);
-            self.set_char(0, left_m.as_bytes()[0]);
-            self.set_char(DIGITS_WIDTH, left_m.as_bytes()[1]);
-            self.set_char(2 * DIGITS_WIDTH - 2, b':');
-            self.set_char(2 * DIGITS_WIDTH + 4, left_s.as_bytes()[0]);
-            self.set_char(3 * DIGITS_WIDTH + 4, left_s.as_bytes()[1]);
+            let seconds = self.value_ms(now_ms) / 1000;
+            let phase = match self.mode {
+                TimerMode::Countdown => self.phase(seconds),
+                TimerMode::Stopwatch => Phase::normal(),
+            };
+            self.draw_time(seconds, &phase);
+            // A paused timer pulses, whatever the phase.
+            let effect = if self.paused {
+                Effect::Pulse
+            } else {
+                phase.effect
+            };
+            self.leds.brightness(effect.brightness(now_ms));
         }
         self.leds.brightness(0.5);
         self.leds.leds.clone()
     }
 
-    fn set_char(&mut self, x: usize, char: u8) {
-        let on = LED::from_rgb(0xa0, 0xa0, 0xa0);
-        for y in 0..8 {
-            let byte = DIGITS[(char - b'0') as usize][7 - y];
-            for b in 0..DIGITS_WIDTH {
-                if (byte >> b) & 1 == 1 {
-                    self.leds.set_u(x + b, y, on.clone());
+    fn draw_expiry(&mut self, now_ms: u128) {
+        match self.phases.expiry {
+            Expiry::Pulse => {
+                let pink =
+                    LED::from_rgb(0xff, 0x80, 0x80).brightness(Effect::Pulse.brightness(now_ms));
+                self.leds.fill(&pink);
+            }
+            Expiry::Blink => {
+                let red = LED::from_rgb(0xff, 0, 0).brightness(Effect::Blink.brightness(now_ms));
+                self.leds.fill(&red);
+            }
+            Expiry::Rainbow => {
+                let count = self.leds.leds.len() as f32;
+                let turn = (now_ms % 4000) as f32 / 4000.;
+                for (i, led) in self.leds.leds.iter_mut().enumerate() {
+                    *led = LED::from_hsv(i as f32 / count * 8. + turn, 1., 1.);
                 }
             }
+            Expiry::Zeros => {
+                let phase = self.phase(0);
+                self.draw_time(0, &phase);
+                self.leds.brightness(Effect::Blink.brightness(now_ms));
+            }
         }
     }
 
-    pub fn get_minutes(&self, now: u128) -> u128 {
-        if now < self.end_ms {
-            (self.end_ms - now) / 1000
+    fn draw_time(&mut self, seconds: u128, phase: &Phase) {
+        self.leds.fill(&phase.background);
+        let mut x = 0;
+        for c in Self::format_time(seconds).chars() {
+            if c == ':' {
+                // The colon is narrower than the digits.
+                self.leds.set_glyph(x - 2, c, phase.digits);
+                x += DIGITS_WIDTH - 3;
+            } else {
+                self.leds.set_glyph(x, c, phase.digits);
+                x += DIGITS_WIDTH;
+            }
+        }
+    }
+
+    // mm:ss, or h:mm:ss for more than 99 minutes.
+    fn format_time(seconds: u128) -> String {
+        if seconds / 60 > 99 {
+            format!(
+                "{}:{:02}:{:02}",
+                seconds / 3600,
+                seconds / 60 % 60,
+                seconds % 60
+            )
         } else {
-            0
+            format!("{:02}:{:02}", seconds / 60, seconds % 60)
         }
     }
+
+    pub fn get_minutes(&self, now: u128) -> u128 {
+        self.value_ms(now) / 1000
+    }
+
+    pub fn mode(&self) -> TimerMode {
+        self.mode
+    }
 }
 
-// Copied from https://github.com/dhepper/font8x8/blob/master/font8x8_basic.h
-const DIGITS_WIDTH: usize = 7;
-const DIGITS: [[u8; 8]; 11] = [
-    [0x3E, 0x63, 0x73, 0x7B, 0x6F, 0x67, 0x3E, 0x00], // U+0030 (0)
-    [0x0C, 0x0E, 0x0C, 0x0C, 0x0C, 0x0C, 0x3F, 0x00], // U+0031 (1)
-    [0x1E, 0x33, 0x30, 0x1C, 0x06, 0x33, 0x3F, 0x00], // U+0032 (2)
-    [0x1E, 0x33, 0x30, 0x1C, 0x30, 0x33, 0x1E, 0x00], // U+0033 (3)
-    [0x38, 0x3C, 0x36, 0x33, 0x7F, 0x30, 0x78, 0x00], // U+0034 (4)
-    [0x3F, 0x03, 0x1F, 0x30, 0x30, 0x33, 0x1E, 0x00], // U+0035 (5)
-    [0x1C, 0x06, 0x03, 0x1F, 0x33, 0x33, 0x1E, 0x00], // U+0036 (6)
-    [0x3F, 0x33, 0x30, 0x18, 0x0C, 0x0C, 0x0C, 0x00], // U+0037 (7)
-    [0x1E, 0x33, 0x33, 0x1E, 0x33, 0x33, 0x1E, 0x00], // U+0038 (8)
-    [0x1E, 0x33, 0x33, 0x3E, 0x30, 0x18, 0x0E, 0x00], // U+0039 (9)
-    [0x00, 0x0C, 0x0C, 0x00, 0x00, 0x0C, 0x0C, 0x00], // U+003A (:)
-];
-
-// const DIGITS_WIDTH: usize = 5;
-// const DIGITS: [[u8; 8]; 11] = [
-//     [0x00, 0x1E, 0x33, 0x33, 0x33, 0x33, 0x33, 0x1E],
-//     [0x00, 0x18, 0x1C, 0x1A, 0x18, 0x18, 0x18, 0x18],
-//     [0x00, 0x1F, 0x33, 0x38, 0x1C, 0x0E, 0x07, 0x3F],
-//     [0x00, 0x3F, 0x18, 0x0C, 0x1E, 0x30, 0x33, 0x1F],
-//     [0x00, 0x18, 0x1C, 0x1A, 0x19, 0x7F, 0x18, 0x18],
-//     [0x00, 0x3F, 0x03, 0x1F, 0x30, 0x30, 0x33, 0x1F],
-//     [0x00, 0x1E, 0x03, 0x1F, 0x33, 0x33, 0x33, 0x1E],
-//     [0x00, 0x3F, 0x30, 0x38, 0x1C, 0x0E, 0x06, 0x06],
-//     [0x00, 0x1E, 0x33, 0x33, 0x1E, 0x33, 0x33, 0x1E],
-//     [0x00, 0x1E, 0x33, 0x33, 0x33, 0x3E, 0x30, 0x1E],
-//     [0x00, 0x00, 0x0C, 0x0C, 0x00, 0x00, 0x0C, 0x0C],
-// ];
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(Countdown::format_time(0), "00:00");
+        assert_eq!(Countdown::format_time(99 * 60 + 59), "99:59");
+        assert_eq!(Countdown::format_time(100 * 60), "1:40:00");
+        assert_eq!(Countdown::format_time(10 * 3600 + 61), "10:01:01");
+    }
+
+    #[test]
+    fn test_phases() {
+        let phase = |below_s, effect| Phase {
+            below_s,
+            background: LED::from_rgb(0xff, 0, 0),
+            digits: LED::black(),
+            effect,
+        };
+        let mut countdown = Countdown::new(Geometry::new(300, 37));
+        countdown.set_phases(CountdownPhases {
+            phases: vec![phase(300, Effect::Steady), phase(10, Effect::Blink)],
+            expiry: Expiry::Zeros,
+        });
+        countdown.set_countdown(1_000_000);
+
+        let below = |now_ms| countdown.get_phase(now_ms).map(|p| p.below_s);
+        assert_eq!(below(1_000_000 - 301_000), None);
+        assert_eq!(below(1_000_000 - 300_000), None);
+        assert_eq!(below(1_000_000 - 299_999), Some(300));
+        assert_eq!(below(1_000_000 - 9_999), Some(10));
+        assert_eq!(below(1_000_001), None);
+
+        // Blinking makes all LEDs dark half of the time
+        let leds = countdown.get_leds(1_000_000 - 9_400);
+        assert!(leds.iter().all(|led| led.red() == 0));
+        let leds = countdown.get_leds(1_000_000 - 9_600);
+        assert!(leds.iter().any(|led| led.red() > 0));
+
+        let mut invalid = CountdownPhases::default();
+        assert!(invalid.is_valid());
+        invalid.phases.push(phase(0, Effect::Pulse));
+        assert!(!invalid.is_valid());
+    }
+
+    #[test]
+    fn test_timer() {
+        let mut timer = Countdown::new(Geometry::new(300, 37));
+        let seconds = |timer: &Countdown, now_ms| timer.get_status(now_ms).seconds;
+        timer.set_countdown(100_000);
+        assert_eq!(seconds(&timer, 40_000), 60);
+
+        timer.pause(40_000);
+        assert_eq!(seconds(&timer, 90_000), 60);
+        assert!(!timer.expired(200_000));
+        timer.add_time(60, 90_000);
+        timer.resume(100_000);
+        assert_eq!(seconds(&timer, 100_000), 120);
+        assert!(!timer.get_status(100_000).paused);
+
+        timer.add_time(-30, 100_000);
+        assert_eq!(seconds(&timer, 100_000), 90);
+        timer.add_time(-300, 100_000);
+        assert_eq!(seconds(&timer, 100_000), 0);
+        assert!(timer.expired(100_001));
+        // Adding time to an expired countdown starts it again
+        timer.add_time(60, 200_000);
+        assert_eq!(seconds(&timer, 230_000), 30);
+
+        timer.start_stopwatch(1_000_000);
+        assert_eq!(seconds(&timer, 1_005_000), 5);
+        assert!(!timer.expired(2_000_000));
+        timer.pause(1_005_000);
+        timer.resume(1_010_000);
+        timer.add_time(60, 1_010_000);
+        let status = timer.get_status(1_012_000);
+        assert_eq!(status.seconds, 67);
+        assert_eq!(status.mode, TimerMode::Stopwatch);
+        assert_eq!(timer.get_phase(1_012_000), None);
+    }
+
+    #[test]
+    fn test_countdown_end() {
+        let ms = 1_769_950_800_000;
+        assert_eq!(CountdownEnd::UnixMs(ms).to_ms(), Some(ms));
+        let rfc = |s: &str| CountdownEnd::Rfc3339(s.into()).to_ms();
+        assert_eq!(rfc("2026-02-01T14:00:00+01:00"), Some(ms));
+        assert_eq!(rfc("2026-02-01T13:00:00Z"), Some(ms));
+        assert_eq!(rfc("2026-02-01 14:00"), None);
+        assert_eq!(rfc("2026-02-01T08:00:00-05:00"), Some(ms));
+        assert_eq!(rfc("1969-12-31T23:59:00Z"), None);
+    }
+}
@@ -1,17 +1,24 @@
 use serde::{Deserialize, Serialize};
 
-use crate::hat::leds::LED;
-use std::{collections::VecDeque, f32::consts::PI};
+use crate::hat::{
+    geometry::Geometry,
+    layout::Layout,
+    leds::{ColorModel, OutputMapping, LED},
+    program::{Program, ProgramError, Syntax},
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::Arc,
+};
 
-#[derive(Default)]
 pub struct Function {
-    leds: usize,
-    // width of the LED wall
-    width: usize,
-    // height of the LED wall
-    height: usize,
-    // Formulas waiting in the queue
+    // Where the LEDs are, to give them their x and y
+    layout: Arc<dyn Layout>,
+    // Formulas waiting in the queue, taking turns between submitters
     queue: VecDeque<Formula>,
+    // Maximum number of formulas one submitter can have in the queue
+    max_per_submitter: usize,
     // The current formula, or None
     current: Option<Formula>,
     // Minimum time a formula is shown, in ms
@@ -20,43 +27,214 @@ pub struct Function {
     time_total: u128,
     // Start of current formula, in ms
     time_start: u128,
+    // Id given to the next formula added to the queue
+    next_id: u64,
+    // How the formula values are shown on the LEDs
+    mapping: OutputMapping,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct FormulaStrings {
     red: String,
     green: String,
     blue: String,
+    // Older clients only send reverse polish notation.
+    #[serde(default)]
+    syntax: Syntax,
+    // With hsv or hsl, red, green, and blue hold the three components
+    // of that model.
+    #[serde(default)]
+    color: ColorModel,
+}
+
+/// The colour channel of a formula.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    Red,
+    Green,
+    Blue,
+}
+
+/// A formula which failed to compile, with the channel it was given for.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FormulaError {
+    pub channel: Channel,
+    #[serde(flatten)]
+    pub error: ProgramError,
+}
+
+impl fmt::Display for FormulaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} formula: {}", self.channel, self.error)
+    }
+}
+
+impl std::error::Error for FormulaError {}
+
+/// Why a formula was not added to the queue.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SubmitError {
+    Invalid(FormulaError),
+    QueueFull { limit: usize },
+}
+
+impl From<FormulaError> for SubmitError {
+    fn from(e: FormulaError) -> Self {
+        SubmitError::Invalid(e)
+    }
+}
+
+impl fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubmitError::Invalid(e) => write!(f, "{e}"),
+            SubmitError::QueueFull { limit } => {
+                write!(f, "already {limit} formulas in the queue")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SubmitError {}
+
+/// A formula in the queue, as shown to the admins.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QueueEntry {
+    pub id: u64,
+    pub submitter: String,
+    // When the formula was added to the queue, in ms since the epoch.
+    pub submitted_ms: u128,
+    pub formula: FormulaStrings,
+}
+
+/// The formula currently shown, and the ones waiting after it.
+#[derive(Debug, Serialize)]
+pub struct QueueListing {
+    pub current: Option<QueueEntry>,
+    pub queue: Vec<QueueEntry>,
 }
 
 impl Function {
-    pub fn new(leds: usize, circum: usize, time_min: u128, time_total: u128) -> Function {
-        println!("{leds} / {circum} / {}", leds / circum);
+    pub fn new(
+        geometry: Geometry,
+        time_min: u128,
+        time_total: u128,
+        max_per_submitter: usize,
+    ) -> Function {
         Function {
-            leds,
-            width: circum * 2 - 1,
-            height: leds / circum,
-            queue: VecDeque::from([Formula::new("t sin".into(), "x".into(), "y".into())]),
+            layout: geometry.layout(),
+            queue: VecDeque::from([Formula::new(FormulaStrings {
+                red: "t sin".into(),
+                green: "x".into(),
+                blue: "y".into(),
+                syntax: Syntax::Rpn,
+                color: ColorModel::Rgb,
+            })
+            .expect("default formula must compile")]),
+            max_per_submitter,
             current: None,
             time_min,
             time_total,
             time_start: 0,
+            next_id: 1,
+            mapping: OutputMapping::default(),
         }
     }
 
-    pub fn add_formula(&mut self, fs: FormulaStrings) {
-        let formula = Formula::new(fs.red, fs.green, fs.blue);
-        if !self.queue.contains(&formula) {
-            self.queue.push_back(formula);
+    /// Adds a formula to the queue, unless the same formula is already
+    /// waiting.
+    /// Submitters take turns: a formula goes behind the last formula of
+    /// every other submitter with fewer formulas queued.
+    /// Returns the compiled formula, to find it in the history.
+    pub fn add_formula(
+        &mut self,
+        fs: FormulaStrings,
+        submitter: String,
+        time_ms: u128,
+    ) -> Result<Compiled, SubmitError> {
+        let mut formula = Formula::new(fs)?;
+        let compiled = formula.compiled.clone();
+        if self.queue.contains(&formula) {
+            return Ok(compiled);
+        }
+        formula.submitter = submitter;
+        formula.submitted_ms = time_ms;
+
+        // The round of each formula is the number of formulas queued before
+        // it by the same submitter.
+        let mut rounds: HashMap<&str, usize> = HashMap::new();
+        let mut position = self.queue.len();
+        let round = self
+            .queue
+            .iter()
+            .filter(|f| f.submitter == formula.submitter)
+            .count();
+        if round >= self.max_per_submitter {
+            return Err(SubmitError::QueueFull {
+                limit: self.max_per_submitter,
+            });
+        }
+        for (i, queued) in self.queue.iter().enumerate() {
+            let r = rounds.entry(&queued.submitter).or_default();
+            if *r > round {
+                position = i;
+                break;
+            }
+            *r += 1;
         }
+        formula.id = self.next_id;
+        self.next_id += 1;
+        self.queue.insert(position, formula);
+        Ok(compiled)
     }
 
-    pub fn _clear_queue(&mut self) {
+    /// Removes all formulas, including the one currently shown.
+    pub fn clear_queue(&mut self) {
         self.queue.clear();
         self.current = None;
     }
 
-    pub fn check_formulas(&mut self, time_ms: u128) {
+    pub fn list_queue(&self) -> QueueListing {
+        QueueListing {
+            current: self.current.as_ref().map(Formula::entry),
+            queue: self.queue.iter().map(Formula::entry).collect(),
+        }
+    }
+
+    /// Removes a waiting formula from the queue.
+    /// Returns false if there is no such formula.
+    pub fn remove_formula(&mut self, id: u64) -> bool {
+        let len = self.queue.len();
+        self.queue.retain(|f| f.id != id);
+        self.queue.len() != len
+    }
+
+    /// Moves a waiting formula to the front of the queue, so it is shown next.
+    /// Returns false if there is no such formula.
+    pub fn move_to_front(&mut self, id: u64) -> bool {
+        let Some(pos) = self.queue.iter().position(|f| f.id == id) else {
+            return false;
+        };
+        if let Some(formula) = self.queue.remove(pos) {
+            self.queue.push_front(formula);
+        }
+        true
+    }
+
+    /// Stops the current formula, so the next one in the queue is started
+    /// right away.
+    /// Returns false if no formula was shown, or none is waiting, as the
+    /// hat would stay black until the next one is sent.
+    pub fn skip_current(&mut self) -> bool {
+        if self.queue.is_empty() {
+            return false;
+        }
+        self.current.take().is_some()
+    }
+
+    /// Returns the formula if a new one has just been started.
+    pub fn check_formulas(&mut self, time_ms: u128) -> Option<&Compiled> {
         // Decide if it's time to go to the next formula, based on the
         // time_min, time_total, and time_start
 
@@ -74,193 +252,121 @@ impl Function {
             if let Some(form) = self.queue.pop_front() {
                 self.current = Some(form);
                 self.time_start = time_ms;
+                return self.current.as_ref().map(|f| &f.compiled);
             }
         }
+        None
     }
 
     pub fn get_leds(&self, time_ms: u128) -> Vec<super::LED> {
         // Calculate LEDs
-        let mut leds = vec![];
-        if let Some(formula) = &self.current {
-            for y in 0..self.height {
-                for x in 0..self.width {
-                    if ((x + y) % 2) == 1 {
-                        // These are the missing LEDs.
-                        continue;
-                    }
-                    let fx = x as f32 / (self.width as f32 - 1.) * 2. - 1.;
-                    let fy = y as f32 / (self.height as f32 - 1.);
-                    let ft = (time_ms - self.time_start) as f32 / 1000.;
-                    leds.push(formula.eval(fx, fy, ft));
-                }
-            }
-        }
-
-        while leds.len() < self.leds {
-            leds.push(LED::black());
-        }
-        leds
+        let Some(formula) = &self.current else {
+            return vec![LED::black(); self.layout.len()];
+        };
+        let ft = (time_ms - self.time_start) as f32 / 1000.;
+        (0..self.layout.len())
+            .map(|index| {
+                let (x, y) = self.layout.normalized(index);
+                formula.compiled.eval(x * 2. - 1., y, ft, &self.mapping)
+            })
+            .collect()
     }
 
     pub fn queue_len(&self) -> usize {
         self.queue.len()
     }
+
+    pub fn set_output_mapping(&mut self, mapping: OutputMapping) {
+        self.mapping = mapping;
+    }
+
+    pub fn output_mapping(&self) -> OutputMapping {
+        self.mapping
+    }
 }
 
-// Stores one formula
-#[derive(PartialEq)]
-struct Formula {
-    // The raw formula as received from the frontend.
-    // It comes as one string per color.
-    red: String,
-    green: String,
-    blue: String,
+/// A formula compiled once, with one program per color.
+/// Two formulas are the same if they compile to the same programs,
+/// however they were written, both in the queue and in the history.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Compiled {
+    red: Program,
+    green: Program,
+    blue: Program,
+    color: ColorModel,
 }
 
-impl Formula {
-    fn new(red: String, green: String, blue: String) -> Self {
-        // For now, we store the raw formula and validate during evaluation
-        // Future enhancement could pre-parse and validate here
-        Self { red, green, blue }
+impl Compiled {
+    pub fn new(source: &FormulaStrings) -> Result<Self, FormulaError> {
+        Ok(Self {
+            red: Self::compile(&source.red, source.syntax, Channel::Red)?,
+            green: Self::compile(&source.green, source.syntax, Channel::Green)?,
+            blue: Self::compile(&source.blue, source.syntax, Channel::Blue)?,
+            color: source.color,
+        })
+    }
+
+    fn compile(raw: &str, syntax: Syntax, channel: Channel) -> Result<Program, FormulaError> {
+        let prog =
+            Program::compile(raw, syntax).map_err(|error| FormulaError { channel, error })?;
+        tracing::debug!(
+            "Compiled '{raw}' with a stack depth of {}",
+            prog.max_depth()
+        );
+        Ok(prog)
     }
 
     // Returns the value of the LED at this position and time.
     // x, y go from 0 to 1, as in the frontend
     // t goes from 0 to infinity.
-    fn eval(&self, x: f32, y: f32, t: f32) -> LED {
-        // Evaluate the formula using reverse Polish notation
-        let red = Self::evaluate_rpn(&self.red, x, y, t);
-        let green = Self::evaluate_rpn(&self.green, x, y, t);
-        let blue = Self::evaluate_rpn(&self.blue, x, y, t);
-
-        // Convert result to LED color
-        LED::from_rgb(red, green, blue)
-    }
-
-    fn evaluate_rpn(raw: &str, x: f32, y: f32, t: f32) -> u8 {
-        let tokens: Vec<&str> = raw.trim().split_whitespace().collect();
-        let mut stack: Vec<f32> = Vec::new();
-
-        for token in tokens {
-            match token {
-                // Numbers
-                token if Self::is_number(token) => {
-                    if let Ok(num) = token.parse::<f32>() {
-                        stack.push(num);
-                    }
-                }
-                // Variables
-                "x" => stack.push(x),
-                "y" => stack.push(y),
-                "t" => stack.push(t),
-                // Binary operators
-                "+" => {
-                    if stack.len() >= 2 {
-                        let b = stack.pop().unwrap();
-                        let a = stack.pop().unwrap();
-                        stack.push(a + b);
-                    }
-                }
-                "-" => {
-                    if stack.len() >= 2 {
-                        let b = stack.pop().unwrap();
-                        let a = stack.pop().unwrap();
-                        stack.push(a - b);
-                    }
-                }
-                "*" => {
-                    if stack.len() >= 2 {
-                        let b = stack.pop().unwrap();
-                        let a = stack.pop().unwrap();
-                        stack.push(a * b);
-                    }
-                }
-                "/" => {
-                    if stack.len() >= 2 {
-                        let b = stack.pop().unwrap();
-                        let a = stack.pop().unwrap();
-                        if b != 0.0 {
-                            stack.push(a / b);
-                        } else {
-                            stack.push(0.0);
-                        }
-                    }
-                }
-                "%" => {
-                    if stack.len() >= 2 {
-                        let b = stack.pop().unwrap();
-                        let a = stack.pop().unwrap();
-                        if b != 0.0 {
-                            stack.push(a % b);
-                        } else {
-                            stack.push(0.0);
-                        }
-                    }
-                }
-                "^" | "pow" => {
-                    if stack.len() >= 2 {
-                        let b = stack.pop().unwrap();
-                        let a = stack.pop().unwrap();
-                        stack.push(a.powf(b));
-                    }
-                }
-                // Unary functions
-                "cos" => {
-                    if let Some(a) = stack.pop() {
-                        stack.push((a * PI).cos());
-                    }
-                }
-                "sin" => {
-                    if let Some(a) = stack.pop() {
-                        stack.push((a * PI).sin());
-                    }
-                }
-                "tan" => {
-                    if let Some(a) = stack.pop() {
-                        stack.push((a * PI).tan());
-                    }
-                }
-                "acos" => {
-                    if let Some(a) = stack.pop() {
-                        stack.push(a.acos() / PI);
-                    }
-                }
-                "asin" => {
-                    if let Some(a) = stack.pop() {
-                        stack.push(a.asin() / PI);
-                    }
-                }
-                "atan" => {
-                    if let Some(a) = stack.pop() {
-                        stack.push(a.atan() / PI);
-                    }
-                }
-                "sqrt" => {
-                    if let Some(a) = stack.pop() {
-                        stack.push(a.sqrt());
-                    }
-                }
-                "exp" => {
-                    if let Some(a) = stack.pop() {
-                        stack.push(a.exp());
-                    }
-                }
-                "abs" => {
-                    if let Some(a) = stack.pop() {
-                        stack.push(a.abs());
-                    }
-                }
-                // Unknown tokens are ignored
-                _ => {}
-            }
-        }
+    fn eval(&self, x: f32, y: f32, t: f32, mapping: &OutputMapping) -> LED {
+        self.color
+            .to_led(
+                mapping.to_unit(self.red.run(x, y, t)),
+                mapping.to_unit(self.green.run(x, y, t)),
+                mapping.to_unit(self.blue.run(x, y, t)),
+            )
+            .map_output(mapping)
+    }
+}
+
+// Stores one formula
+#[derive(Debug)]
+struct Formula {
+    // The formula as received from the frontend.
+    source: FormulaStrings,
+    // Identifies the formula in the queue, for the admins.
+    id: u64,
+    // Who sent the formula, and when, in ms.
+    submitter: String,
+    submitted_ms: u128,
+    compiled: Compiled,
+}
 
-        // Return the top of the stack or 0 if empty
-        (stack.pop().unwrap_or(0.0) / 2. * 256. + 128.).clamp(0., 255.) as u8 / 4
+impl PartialEq for Formula {
+    fn eq(&self, other: &Self) -> bool {
+        self.compiled == other.compiled
     }
+}
 
-    fn is_number(token: &str) -> bool {
-        token.parse::<f32>().is_ok()
+impl Formula {
+    fn new(source: FormulaStrings) -> Result<Self, FormulaError> {
+        Ok(Self {
+            compiled: Compiled::new(&source)?,
+            source,
+            id: 0,
+            submitter: String::new(),
+            submitted_ms: 0,
+        })
+    }
+
+    fn entry(&self) -> QueueEntry {
+        QueueEntry {
+            id: self.id,
+            submitter: self.submitter.clone(),
+            submitted_ms: self.submitted_ms,
+            formula: self.source.clone(),
+        }
     }
 }
 
@@ -268,25 +374,335 @@ impl Formula {
 mod test {
     use super::*;
 
+    fn formula(
+        red: &str,
+        green: &str,
+        blue: &str,
+        syntax: Syntax,
+        color: ColorModel,
+    ) -> Result<Formula, FormulaError> {
+        Formula::new(FormulaStrings {
+            red: red.into(),
+            green: green.into(),
+            blue: blue.into(),
+            syntax,
+            color,
+        })
+    }
+
     #[test]
     fn test_range() {
-        let form = Formula::new("x cos".into(), "y cos".into(), "t cos".into());
-        let led = form.eval(0., 0., 0.);
+        let form = formula("x cos", "y cos", "t cos", Syntax::Rpn, ColorModel::Rgb).unwrap();
+        let led = form.compiled.eval(0., 0., 0., &OutputMapping::default());
         println!("{led:?}");
-        let led = form.eval(-1., 0., 0.);
+        let led = form.compiled.eval(-1., 0., 0., &OutputMapping::default());
         println!("{led:?}");
-        let led = form.eval(1., 1., 1.);
+        let led = form.compiled.eval(1., 1., 1., &OutputMapping::default());
         println!("{led:?}");
     }
 
     #[test]
     fn test_function() {
-        let mut func = Function::new(10, 5, 10, 10);
-        func.add_formula(FormulaStrings {
-            red: "x cos".into(),
-            green: "y cos".into(),
-            blue: "t cos".into(),
-        });
+        let mut func = Function::new(Geometry::new(10, 5), 10, 10, 3);
+        func.add_formula(
+            FormulaStrings {
+                red: "x cos".into(),
+                green: "y cos".into(),
+                blue: "t cos".into(),
+                syntax: Syntax::Rpn,
+                color: ColorModel::Rgb,
+            },
+            "alice".into(),
+            0,
+        )
+        .unwrap();
         println!("{:?}", func.get_leds(0));
     }
+
+    #[test]
+    fn test_channel() {
+        let form = formula("-1", "0", "1", Syntax::Rpn, ColorModel::Rgb).unwrap();
+        let led = form.compiled.eval(0., 0., 0., &OutputMapping::default());
+        assert_eq!((led.red(), led.green(), led.blue()), (0, 32, 63));
+        let form = formula("x", "y cos", "", Syntax::Rpn, ColorModel::Rgb).unwrap();
+        let led = form.compiled.eval(-2., 0., 0., &OutputMapping::default());
+        assert_eq!((led.red(), led.green(), led.blue()), (0, 63, 32));
+    }
+
+    fn submit(func: &mut Function, red: &str, submitter: &str) -> Result<Compiled, SubmitError> {
+        func.add_formula(
+            FormulaStrings {
+                red: red.into(),
+                green: "".into(),
+                blue: "".into(),
+                syntax: Syntax::Rpn,
+                color: ColorModel::Rgb,
+            },
+            submitter.into(),
+            0,
+        )
+    }
+
+    #[test]
+    fn test_fairness() {
+        let mut func = Function::new(Geometry::new(10, 5), 10, 10, 3);
+        func.clear_queue();
+        for red in ["1", "2", "3"] {
+            submit(&mut func, red, "alice").unwrap();
+        }
+        assert_eq!(
+            submit(&mut func, "4", "alice"),
+            Err(SubmitError::QueueFull { limit: 3 })
+        );
+        // Same formula is accepted, but not queued twice
+        submit(&mut func, "1", "bob").unwrap();
+        submit(&mut func, "-1", "bob").unwrap();
+        submit(&mut func, "-2", "bob").unwrap();
+        submit(&mut func, "0.5", "carol").unwrap();
+
+        let order: Vec<_> = func
+            .queue
+            .iter()
+            .map(|f| (f.submitter.as_str(), f.source.red.as_str()))
+            .collect();
+        assert_eq!(
+            order,
+            [
+                ("alice", "1"),
+                ("bob", "-1"),
+                ("carol", "0.5"),
+                ("alice", "2"),
+                ("bob", "-2"),
+                ("alice", "3"),
+            ]
+        );
+
+        // Once alice's formulas are shown, she can submit again
+        func.check_formulas(0);
+        submit(&mut func, "4", "alice").unwrap();
+        assert_eq!(func.queue.back().unwrap().source.red, "4");
+    }
+
+    #[test]
+    fn test_moderation() {
+        let mut func = Function::new(Geometry::new(10, 5), 10, 10, 3);
+        func.clear_queue();
+        for red in ["1", "2", "3"] {
+            submit(&mut func, red, "alice").unwrap();
+        }
+        let queued = |func: &Function| -> Vec<(u64, String)> {
+            func.list_queue()
+                .queue
+                .into_iter()
+                .map(|e| (e.id, e.formula.red))
+                .collect()
+        };
+        assert_eq!(
+            queued(&func),
+            [(1, "1".into()), (2, "2".into()), (3, "3".into())]
+        );
+
+        assert!(func.move_to_front(3));
+        assert!(func.remove_formula(1));
+        assert!(!func.remove_formula(1));
+        assert!(!func.move_to_front(1));
+        assert_eq!(queued(&func), [(3, "3".into()), (2, "2".into())]);
+
+        assert!(!func.skip_current());
+        func.check_formulas(0);
+        let listing = func.list_queue();
+        let current = listing.current.unwrap();
+        assert_eq!((current.id, current.submitter.as_str()), (3, "alice"));
+        assert_eq!(listing.queue.len(), 1);
+
+        // Skipping starts the next formula right away
+        assert!(func.skip_current());
+        func.check_formulas(1);
+        assert_eq!(func.list_queue().current.unwrap().id, 2);
+        // But not to nothing
+        assert!(!func.skip_current());
+        assert_eq!(func.list_queue().current.unwrap().id, 2);
+
+        func.clear_queue();
+        let listing = func.list_queue();
+        assert!(listing.current.is_none() && listing.queue.is_empty());
+    }
+
+    #[test]
+    fn test_formula_error() {
+        let mut func = Function::new(Geometry::new(10, 5), 10, 10, 3);
+        let err = func
+            .add_formula(
+                FormulaStrings {
+                    red: "x".into(),
+                    green: "y".into(),
+                    blue: "t +".into(),
+                    syntax: Syntax::Rpn,
+                    color: ColorModel::Rgb,
+                },
+                "alice".into(),
+                0,
+            )
+            .unwrap_err();
+        let SubmitError::Invalid(err) = err else {
+            panic!("Expected an invalid formula, got {err:?}");
+        };
+        assert_eq!(err.channel, Channel::Blue);
+        assert_eq!(
+            err.error,
+            ProgramError::StackUnderflow {
+                index: 1,
+                token: "+".into()
+            }
+        );
+        assert_eq!(func.queue_len(), 1);
+    }
+
+    // Evaluates a single channel formula, without mapping it to a LED.
+    fn value(raw: &str) -> f32 {
+        let form = formula(raw, "", "", Syntax::Rpn, ColorModel::Rgb).unwrap();
+        form.compiled.red.run(0.25, 0.5, 2.)
+    }
+
+    fn assert_value(raw: &str, expected: f32) {
+        let v = value(raw);
+        assert!((v - expected).abs() < 1e-6, "{raw}: {v} != {expected}");
+    }
+
+    #[test]
+    fn test_shader_operators() {
+        assert_value("x y min", 0.25);
+        assert_value("x y max", 0.5);
+        assert_value("-1.5 floor", -2.);
+        assert_value("-1.5 ceil", -1.);
+        assert_value("-1.25 fract", 0.75);
+        assert_value("t 0 1 clamp", 1.);
+        assert_value("-3 0 1 clamp", 0.);
+        assert_value("x 0 1 clamp", 0.25);
+        assert_value("0 2 x mix", 0.5);
+        assert_value("y x step", 0.);
+        assert_value("y y step", 1.);
+        assert_value("0 1 x smoothstep", 0.15625);
+        assert_value("0 1 t smoothstep", 1.);
+        assert_value("1 1 x smoothstep", 0.);
+        assert_value("x neg sign", -1.);
+        assert_value("0 sign", 0.);
+        assert_value("t ln", 2f32.ln());
+        assert_value("3 4 hypot", 5.);
+        assert_value("1 0 atan2", 0.5);
+        assert_value("0 -1 atan2", 1.);
+    }
+
+    #[test]
+    fn test_shader_operators_arity() {
+        for (raw, index) in [("x y clamp", 2), ("x mix", 1), ("x hypot", 1), ("ln", 0)] {
+            let err = formula(raw, "", "", Syntax::Rpn, ColorModel::Rgb).unwrap_err();
+            assert_eq!(
+                err.error,
+                ProgramError::StackUnderflow {
+                    index,
+                    token: raw.split_whitespace().last().unwrap().into()
+                }
+            );
+        }
+        let form = formula("clamp(x, 0, y)", "", "", Syntax::Infix, ColorModel::Rgb);
+        assert_eq!(form.unwrap().compiled.red, rpn("x 0 y clamp"));
+    }
+
+    fn rpn(raw: &str) -> Program {
+        Program::compile(raw, Syntax::Rpn).unwrap()
+    }
+
+    // Returns the red channel of a formula, 63 being true and 32 false.
+    fn red(raw: &str, syntax: Syntax, x: f32, y: f32) -> u8 {
+        let form = formula(raw, "", "", syntax, ColorModel::Rgb).unwrap();
+        form.compiled
+            .eval(x, y, 0., &OutputMapping::default())
+            .red()
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let (yes, no) = (63, 32);
+        for (raw, lt, eq, gt) in [
+            ("x y <", yes, no, no),
+            ("x y >", no, no, yes),
+            ("x y <=", yes, yes, no),
+            ("x y >=", no, yes, yes),
+            ("x y ==", no, yes, no),
+        ] {
+            assert_eq!(red(raw, Syntax::Rpn, 0.25, 0.5), lt, "{raw} with x < y");
+            assert_eq!(red(raw, Syntax::Rpn, 0.5, 0.5), eq, "{raw} with x == y");
+            assert_eq!(red(raw, Syntax::Rpn, 0.75, 0.5), gt, "{raw} with x > y");
+        }
+    }
+
+    #[test]
+    fn test_logic() {
+        let (yes, no) = (63, 32);
+        for (x, y, and, or) in [(0., 0., no, no), (1., 0., no, yes), (1., -1., yes, yes)] {
+            assert_eq!(red("x y and", Syntax::Rpn, x, y), and, "{x} and {y}");
+            assert_eq!(red("x y or", Syntax::Rpn, x, y), or, "{x} or {y}");
+        }
+        assert_eq!(red("x not", Syntax::Rpn, 0., 0.), yes);
+        assert_eq!(red("x not", Syntax::Rpn, 0.5, 0.), no);
+
+        // Stripes: 1 on the left, -1 on the right
+        assert_eq!(red("x 0 < 1 -1 ?", Syntax::Rpn, -0.5, 0.), 63);
+        assert_eq!(red("x 0 < 1 -1 ?", Syntax::Rpn, 0.5, 0.), 0);
+        assert_eq!(red("x < 0 ? 1 : -1", Syntax::Infix, -0.5, 0.), 63);
+        assert_eq!(red("x < 0 ? 1 : -1", Syntax::Infix, 0.5, 0.), 0);
+    }
+
+    #[test]
+    fn test_color_model() {
+        let form = formula("x", "1", "1", Syntax::Rpn, ColorModel::Hsv).unwrap();
+        let rgb = |led: LED| (led.red(), led.green(), led.blue());
+        let mapping = OutputMapping::default();
+        assert_eq!(rgb(form.compiled.eval(-1., 0., 0., &mapping)), (63, 0, 0));
+        assert_eq!(rgb(form.compiled.eval(1., 0., 0., &mapping)), (63, 0, 0));
+        assert_eq!(
+            rgb(form.compiled.eval(-1. / 3., 0., 0., &mapping)),
+            (0, 63, 0)
+        );
+
+        let fs: FormulaStrings =
+            serde_json::from_str(r#"{"red": "x", "green": "1", "blue": "0", "color": "hsl"}"#)
+                .unwrap();
+        assert_eq!(fs.color, ColorModel::Hsl);
+        let form = Formula::new(fs).unwrap();
+        assert_eq!(
+            rgb(form.compiled.eval(1. / 3., 0., 0., &mapping)),
+            (0, 0, 63)
+        );
+    }
+
+    #[test]
+    fn test_output_mapping() {
+        let form = formula("x", "", "", Syntax::Rpn, ColorModel::Rgb).unwrap();
+        let mapping = OutputMapping {
+            input_min: 0.,
+            input_max: 2.,
+            max_value: 255,
+            gamma: 2.,
+        };
+        assert_eq!(form.compiled.eval(0., 0., 0., &mapping).red(), 0);
+        assert_eq!(form.compiled.eval(1., 0., 0., &mapping).red(), 64);
+        assert_eq!(form.compiled.eval(3., 0., 0., &mapping).red(), 254);
+    }
+
+    #[test]
+    fn test_syntax() {
+        let fs: FormulaStrings =
+            serde_json::from_str(r#"{"red": "t x + sin", "green": "", "blue": ""}"#).unwrap();
+        assert_eq!(fs.syntax, Syntax::Rpn);
+        let rpn = Formula::new(fs).unwrap();
+
+        let fs: FormulaStrings = serde_json::from_str(
+            r#"{"red": "sin(t + x)", "green": "", "blue": "", "syntax": "infix"}"#,
+        )
+        .unwrap();
+        let infix = Formula::new(fs).unwrap();
+        assert_eq!(rpn, infix);
+    }
 }
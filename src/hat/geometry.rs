@@ -0,0 +1,250 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::hat::{
+    layout::{Layout, Matrix, Ring, Spiral},
+    leds::LED,
+};
+use crate::protocol::Response;
+
+/// Maximum number of LEDs, so that their RGB bytes and the header of the
+/// response fit in a single UDP datagram of at most 65507 bytes.
+pub const LEDS_MAX: usize = (65_507 - Response::HEADER_LEN) / 3;
+
+/// In which direction the LED strip spirals up the hat, seen from above.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    #[default]
+    Clockwise,
+    CounterClockwise,
+}
+
+/// How the LEDs are arranged on the wearable.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LayoutKind {
+    // The LEDs spiral up the hat, see [Spiral].
+    #[default]
+    Spiral,
+    // Rows of `circumference` LEDs, see [Matrix].
+    Matrix,
+    // A single circle of LEDs, see [Ring].
+    Ring,
+}
+
+/// How the LEDs are placed on the hat.
+/// By default the LEDs spiral from the bottom to the top, and when they are
+/// interleaved, every row is shifted by half an LED, so the pictures are
+/// drawn on a grid of twice the circumference where only every second
+/// position has an LED.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct Geometry {
+    #[serde(default)]
+    pub layout: LayoutKind,
+    pub leds: usize,
+    // Number of LEDs in one turn of the spiral, or in one row of a matrix.
+    #[serde(default)]
+    pub circumference: usize,
+    // The LED of the spiral where the strip is connected.
+    #[serde(default)]
+    pub start: usize,
+    #[serde(default)]
+    pub direction: Direction,
+    #[serde(default = "Geometry::default_interleaved")]
+    pub interleaved: bool,
+    // Every second row of a matrix is wired the other way.
+    #[serde(default)]
+    pub serpentine: bool,
+}
+
+impl Geometry {
+    /// The interleaved spiral of the original hat.
+    pub fn new(leds: usize, circumference: usize) -> Self {
+        Self {
+            layout: LayoutKind::Spiral,
+            leds,
+            circumference,
+            start: 0,
+            direction: Direction::Clockwise,
+            interleaved: true,
+            serpentine: false,
+        }
+    }
+
+    fn default_interleaved() -> bool {
+        true
+    }
+
+    /// Returns a description of what is wrong with the geometry.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.leds == 0 {
+            return Err("the hat needs at least one LED".into());
+        }
+        if self.leds > LEDS_MAX {
+            return Err(format!(
+                "{} LEDs don't fit in one UDP response, the maximum is {LEDS_MAX}",
+                self.leds
+            ));
+        }
+        match self.layout {
+            LayoutKind::Spiral => {
+                if self.circumference < 2 {
+                    return Err(format!(
+                        "a circumference of {} LEDs doesn't go around the hat",
+                        self.circumference
+                    ));
+                }
+                if self.circumference > self.leds {
+                    return Err(format!(
+                        "a circumference of {} LEDs is more than the {} LEDs of the hat",
+                        self.circumference, self.leds
+                    ));
+                }
+                if self.layout().size().1 < 2 {
+                    return Err("the LEDs must make at least two rows".into());
+                }
+            }
+            LayoutKind::Matrix => {
+                if self.circumference == 0 || self.circumference > self.leds {
+                    return Err(format!(
+                        "a matrix cannot have rows of {} of its {} LEDs",
+                        self.circumference, self.leds
+                    ));
+                }
+            }
+            LayoutKind::Ring => {}
+        }
+        if self.start >= self.leds {
+            return Err(format!(
+                "the strip cannot start at LED {} of {} LEDs",
+                self.start, self.leds
+            ));
+        }
+        Ok(())
+    }
+
+    /// The mapping between the LEDs and the grid of the pictures.
+    pub fn layout(&self) -> Arc<dyn Layout> {
+        match self.layout {
+            LayoutKind::Spiral => Arc::new(Spiral {
+                leds: self.leds,
+                circumference: self.circumference,
+                interleaved: self.interleaved,
+            }),
+            LayoutKind::Matrix => Arc::new(Matrix {
+                leds: self.leds,
+                width: self.circumference,
+                serpentine: self.serpentine,
+            }),
+            LayoutKind::Ring => Arc::new(Ring { leds: self.leds }),
+        }
+    }
+
+    /// Orders the LEDs as they are wired on the strip.
+    /// A strip going the other way shows the picture mirrored, so it is
+    /// mirrored here once more.
+    pub fn wire(&self, leds: &[LED]) -> Vec<LED> {
+        let layout = self.layout();
+        (0..leds.len())
+            .map(|i| {
+                let mut index = (i + self.start) % leds.len();
+                if self.direction == Direction::CounterClockwise {
+                    let (x, y) = layout.position(index);
+                    let mirror = layout.size().0 - 1 - x;
+                    index = layout.index(mirror as i64, y as i64).unwrap_or(leds.len());
+                }
+                leds.get(index).copied().unwrap_or_else(LED::black)
+            })
+            .collect()
+    }
+}
+
+impl Default for Geometry {
+    fn default() -> Self {
+        Self::new(300, 37)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_geometry() {
+        let hat = Geometry::new(300, 37);
+        assert_eq!(hat.layout().size(), (73, 9));
+        assert_eq!(hat.layout().index(1, 1), Some(37));
+        assert!(hat.validate().is_ok());
+
+        let plain = Geometry {
+            interleaved: false,
+            ..Geometry::new(6, 3)
+        };
+        assert_eq!(plain.layout().size(), (3, 2));
+        assert_eq!(plain.layout().index(0, 1), Some(3));
+
+        assert!(Geometry::new(10, 20).validate().is_err());
+        assert!(Geometry::new(10, 1).validate().is_err());
+        let start = Geometry {
+            start: 10,
+            ..Geometry::new(10, 3)
+        };
+        assert!(start.validate().is_err());
+        assert!(Geometry::new(LEDS_MAX, 37).validate().is_ok());
+        assert!(Geometry::new(LEDS_MAX + 1, 37).validate().is_err());
+
+        // A ring has no rows, a matrix needs its width.
+        let ring = Geometry {
+            layout: LayoutKind::Ring,
+            ..Geometry::new(24, 0)
+        };
+        assert!(ring.validate().is_ok());
+        assert_eq!(ring.layout().size(), (24, 1));
+        let matrix = Geometry {
+            layout: LayoutKind::Matrix,
+            ..Geometry::new(24, 0)
+        };
+        assert!(matrix.validate().is_err());
+        let matrix = Geometry {
+            circumference: 8,
+            ..matrix
+        };
+        assert!(matrix.validate().is_ok());
+        assert_eq!(matrix.layout().size(), (8, 3));
+    }
+
+    #[test]
+    fn test_wire() {
+        let leds: Vec<_> = (0..6).map(|i| LED::from_rgb(i, 0, 0)).collect();
+        let red = |leds: Vec<LED>| leds.iter().map(|l| l.red()).collect::<Vec<_>>();
+        let mut plain = Geometry {
+            interleaved: false,
+            start: 1,
+            ..Geometry::new(6, 3)
+        };
+        assert_eq!(red(plain.wire(&leds)), vec![1, 2, 3, 4, 5, 0]);
+        plain.start = 0;
+        plain.direction = Direction::CounterClockwise;
+        assert_eq!(red(plain.wire(&leds)), vec![2, 1, 0, 5, 4, 3]);
+
+        // Interleaved rows are mirrored onto LEDs as well, but the partial
+        // top row has nothing to mirror.
+        let hat = Geometry {
+            direction: Direction::CounterClockwise,
+            ..Geometry::new(6, 3)
+        };
+        assert_eq!(hat.layout().size().1, 3);
+        assert_eq!(red(hat.wire(&leds)), vec![2, 1, 0, 4, 3, 0]);
+
+        // A serpentine matrix is mirrored row by row.
+        let matrix = Geometry {
+            layout: LayoutKind::Matrix,
+            serpentine: true,
+            direction: Direction::CounterClockwise,
+            ..Geometry::new(6, 3)
+        };
+        assert_eq!(red(matrix.wire(&leds)), vec![2, 1, 0, 5, 4, 3]);
+    }
+}
@@ -0,0 +1,245 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::mpsc::{self, Sender},
+    thread::{self, JoinHandle},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::hat::function::{Compiled, FormulaStrings};
+
+/// Maximum number of entries returned in one page.
+pub const PAGE_MAX: usize = 100;
+
+/// One formula accepted by the hat.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: u64,
+    // When the formula was first submitted, in ms since the epoch.
+    pub time_ms: u128,
+    pub formula: FormulaStrings,
+    // How many times the formula has been shown.
+    pub plays: u64,
+}
+
+/// A page of the history, newest entries first.
+#[derive(Debug, Serialize)]
+pub struct HistoryPage {
+    pub total: usize,
+    pub entries: Vec<HistoryEntry>,
+}
+
+// The lines of the history file.
+// The file is only ever appended to, and replayed on startup.
+#[derive(Debug, Serialize, Deserialize)]
+enum HistoryEvent {
+    Added(HistoryEntry),
+    Played { id: u64 },
+}
+
+// Appends the lines of the history to its file, so the hats never wait
+// for the disk.
+struct Writer {
+    lines: Sender<String>,
+    thread: JoinHandle<()>,
+}
+
+impl Writer {
+    fn spawn(path: PathBuf) -> Self {
+        let (lines, received) = mpsc::channel::<String>();
+        let thread = thread::spawn(move || {
+            for line in received {
+                let written = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .and_then(|mut file| writeln!(file, "{line}"));
+                if let Err(e) = written {
+                    tracing::error!("Couldn't write history to {path:?}: {e}");
+                }
+            }
+        });
+        Self { lines, thread }
+    }
+}
+
+/// Keeps all formulas ever accepted, and writes them to an append-only
+/// JSONL file, if one is given.
+#[derive(Default)]
+pub struct History {
+    writer: Option<Writer>,
+    entries: Vec<HistoryEntry>,
+    // The index of the entry of every compiled formula, to find it however
+    // it is written.
+    // Entries which don't compile anymore are left out.
+    index: HashMap<Compiled, usize>,
+}
+
+impl History {
+    /// Reads the history from the given file, or starts a new one if the
+    /// file doesn't exist yet.
+    /// Lines which cannot be parsed are skipped.
+    pub fn open(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let mut history = History::default();
+        if let Ok(file) = File::open(&path) {
+            for (nbr, line) in BufReader::new(file).lines().enumerate() {
+                match line.map(|l| serde_json::from_str::<HistoryEvent>(&l)) {
+                    Ok(Ok(event)) => history.apply(event),
+                    _ => tracing::warn!("Skipping invalid history line {}", nbr + 1),
+                }
+            }
+            tracing::info!("Loaded {} formulas from history", history.entries.len());
+        }
+        history.writer = Some(Writer::spawn(path));
+        history
+    }
+
+    /// Records a formula and returns its id.
+    /// A formula already in the history keeps its id, like in the queue.
+    pub fn add(&mut self, formula: &FormulaStrings, compiled: Compiled, time_ms: u128) -> u64 {
+        if let Some(&index) = self.index.get(&compiled) {
+            return self.entries[index].id;
+        }
+        let entry = HistoryEntry {
+            id: self.entries.last().map(|e| e.id + 1).unwrap_or_default(),
+            time_ms,
+            formula: formula.clone(),
+            plays: 0,
+        };
+        let id = entry.id;
+        self.write(&HistoryEvent::Added(entry.clone()));
+        self.insert(entry, Some(compiled));
+        id
+    }
+
+    /// Counts one more play of this formula, if it is in the history.
+    pub fn played(&mut self, compiled: &Compiled) {
+        if let Some(&index) = self.index.get(compiled) {
+            let entry = &mut self.entries[index];
+            entry.plays += 1;
+            let id = entry.id;
+            self.write(&HistoryEvent::Played { id });
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<&HistoryEntry> {
+        self.entries.iter().find(|e| e.id == id)
+    }
+
+    /// Returns up to [PAGE_MAX] entries, skipping the `offset` newest ones.
+    pub fn page(&self, offset: usize, limit: usize) -> HistoryPage {
+        HistoryPage {
+            total: self.entries.len(),
+            entries: self
+                .entries
+                .iter()
+                .rev()
+                .skip(offset)
+                .take(limit.min(PAGE_MAX))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    fn write(&self, event: &HistoryEvent) {
+        if let Some(writer) = &self.writer {
+            match serde_json::to_string(event) {
+                Ok(line) => {
+                    // Only fails if the writer stopped, which it never does
+                    // before the history is dropped.
+                    let _ = writer.lines.send(line);
+                }
+                Err(e) => tracing::error!("Couldn't serialize history event: {e}"),
+            }
+        }
+    }
+
+    fn insert(&mut self, entry: HistoryEntry, compiled: Option<Compiled>) {
+        if let Some(compiled) = compiled {
+            self.index.entry(compiled).or_insert(self.entries.len());
+        }
+        self.entries.push(entry);
+    }
+
+    // Replays one line of the history file.
+    fn apply(&mut self, event: HistoryEvent) {
+        match event {
+            HistoryEvent::Added(entry) => {
+                let compiled = Compiled::new(&entry.formula).ok();
+                self.insert(entry, compiled);
+            }
+            HistoryEvent::Played { id } => {
+                if let Some(entry) = self.entries.iter_mut().find(|e| e.id == id) {
+                    entry.plays += 1;
+                }
+            }
+        }
+    }
+}
+
+// Waits for the lines still to be written.
+impl Drop for History {
+    fn drop(&mut self) {
+        if let Some(Writer { lines, thread }) = self.writer.take() {
+            drop(lines);
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn formula(red: &str) -> FormulaStrings {
+        serde_json::from_str(&format!(r#"{{"red": "{red}", "green": "y", "blue": "t"}}"#)).unwrap()
+    }
+
+    fn compiled(red: &str) -> Compiled {
+        Compiled::new(&formula(red)).unwrap()
+    }
+
+    fn add(history: &mut History, red: &str, time_ms: u128) -> u64 {
+        history.add(&formula(red), compiled(red), time_ms)
+    }
+
+    #[test]
+    fn test_history() {
+        let path =
+            std::env::temp_dir().join(format!("led_hat_history_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut history = History::open(&path);
+        assert_eq!(add(&mut history, "x", 10), 0);
+        assert_eq!(add(&mut history, "x sin", 20), 1);
+        assert_eq!(add(&mut history, "x", 30), 0);
+        history.played(&compiled("x"));
+        history.played(&compiled("x"));
+        history.played(&compiled("x sin"));
+        history.played(&compiled("y"));
+
+        let page = history.page(0, 1);
+        assert_eq!(page.total, 2);
+        assert_eq!(page.entries.len(), 1);
+        assert_eq!(page.entries[0].formula, formula("x sin"));
+
+        // Written differently, but the same as in the queue
+        assert_eq!(add(&mut history, "x   sin", 40), 1);
+        history.played(&compiled("x   sin"));
+
+        // Everything is read back from the file
+        drop(history);
+        let reloaded = History::open(&path);
+        let entry = reloaded.get(0).unwrap();
+        assert_eq!((entry.time_ms, entry.plays), (10, 2));
+        assert_eq!(reloaded.get(1).unwrap().plays, 2);
+        assert_eq!(reloaded.page(1, 10).entries, vec![entry.clone()]);
+        assert!(reloaded.get(2).is_none());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,8 +1,17 @@
 use serde::{Deserialize, Serialize};
 
-use crate::hat::leds::{LEDCriss, LED};
+use crate::hat::{
+    geometry::Geometry,
+    leds::{LEDCriss, LED},
+    motion::{Animation, Motion},
+    registry::{IconDef, IconRegistry, IconUpload},
+    sprite::{Frame, Playback, Sprite},
+};
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+/// The icons are sent and shown by their name, so an uploaded icon is
+/// used the same way as a built-in one.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(from = "String", into = "String")]
 pub enum IconType {
     Empty,
     Test,
@@ -13,23 +22,101 @@ pub enum IconType {
     TiTi,
     Fosdem,
     Co2,
+    // Uploaded by the admin, see [IconRegistry].
+    Custom(String),
+}
+
+impl IconType {
+    /// The icons compiled into the hat, in the order they are listed.
+    pub const BUILTIN: [IconType; 9] = [
+        IconType::Fosdem,
+        IconType::Co2,
+        IconType::Empty,
+        IconType::Test,
+        IconType::Pumpkin,
+        IconType::Fish,
+        IconType::Pacman,
+        IconType::BlackAlps,
+        IconType::TiTi,
+    ];
+
+    pub fn name(&self) -> &str {
+        match self {
+            IconType::Empty => "Empty",
+            IconType::Test => "Test",
+            IconType::Pumpkin => "Pumpkin",
+            IconType::Fish => "Fish",
+            IconType::Pacman => "Pacman",
+            IconType::BlackAlps => "BlackAlps",
+            IconType::TiTi => "TiTi",
+            IconType::Fosdem => "Fosdem",
+            IconType::Co2 => "Co2",
+            IconType::Custom(name) => name,
+        }
+    }
+}
+
+impl From<String> for IconType {
+    fn from(name: String) -> Self {
+        Self::BUILTIN
+            .into_iter()
+            .find(|icon| icon.name() == name)
+            .unwrap_or(IconType::Custom(name))
+    }
+}
+
+impl From<IconType> for String {
+    fn from(icon: IconType) -> Self {
+        icon.name().into()
+    }
 }
 
 pub struct Icon {
     leds: LEDCriss,
     icon: IconType,
     co2: u16,
+    registry: IconRegistry,
 }
 
 impl Icon {
-    pub fn new(leds: usize, circum: usize) -> Self {
+    pub fn new(geometry: Geometry, registry: IconRegistry) -> Self {
         Self {
-            leds: LEDCriss::new(leds, circum),
+            leds: LEDCriss::new(geometry),
             icon: IconType::Fish,
             co2: 0,
+            registry,
+        }
+    }
+
+    /// Adds or replaces an uploaded icon, if it fits on the hat.
+    pub fn upload(&mut self, upload: IconUpload) -> bool {
+        upload
+            .to_icon(self.leds.range)
+            .is_some_and(|icon| self.registry.insert(icon))
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.registry.remove(name)
+    }
+
+    /// Whether the icon is built-in or has been uploaded.
+    pub fn exists(&self, icon: &IconType) -> bool {
+        match icon {
+            IconType::Custom(name) => self.registry.get(name).is_some(),
+            _ => true,
         }
     }
 
+    /// The names of all icons, the built-in ones first.
+    pub fn names(&self) -> Vec<String> {
+        IconType::BUILTIN
+            .iter()
+            .map(|icon| icon.name())
+            .chain(self.registry.names())
+            .map(String::from)
+            .collect()
+    }
+
     pub fn set_icon(&mut self, icon: IconType) {
         self.icon = icon;
     }
@@ -39,87 +126,8 @@ impl Icon {
     }
 
     pub fn get_leds(&mut self, time: u128) -> Vec<super::LED> {
-        match self.icon {
+        match &self.icon {
             IconType::Empty => {}
-            IconType::Test => {
-                self.leds.clear();
-                for i in 0..=(self.leds.range.1) {
-                    self.leds.set(
-                        40. + ((time as f64 / 2000.).sin() * 3.) as f32,
-                        i as f32,
-                        &LED::from_hex("800000"),
-                    );
-                }
-            }
-            IconType::Pumpkin => {
-                self.draw_icon(
-                    20. + ((time as f64 / 2000.).sin() * 3.) as f32,
-                    0.,
-                    PUMPKIN,
-                    vec![LED::black(), LED::from_hex("402000")],
-                );
-            }
-            IconType::Fish => {
-                self.draw_icon(
-                    ((time % 37000) as f64 / 500.) as f32,
-                    0.,
-                    FISH,
-                    vec![LED::black(), LED::from_hex("204040")],
-                );
-            }
-            IconType::TiTi => {
-                self.draw_icon(
-                    ((37000 - (time % 37000)) as f64 / 500.) as f32,
-                    0.,
-                    TITI,
-                    vec![
-                        LED::black(),
-                        LED::from_hex("603030"),
-                        LED::from_hex("604030"),
-                        LED::from_hex("605030"),
-                        LED::from_hex("606030"),
-                    ],
-                );
-            }
-            IconType::BlackAlps => {
-                self.draw_icon(
-                    ((time % 37000) as f64 / 500.) as f32,
-                    0.,
-                    BLACKALPS,
-                    vec![LED::black(), LED::from_hex("103030")],
-                );
-            }
-            IconType::Pacman => {
-                let posx = (time % 74000) / 100;
-                let pacman_index = (((posx as i32 / 1) % 7) - 3).abs() as usize;
-                let pacman = PACMAN[pacman_index];
-                self.draw_icon(
-                    posx as f32 / 2.,
-                    0.,
-                    pacman,
-                    vec![
-                        LED::black(),
-                        LED::from_hex("303010"),
-                        LED::from_hex("a05050"),
-                    ],
-                );
-            }
-            IconType::Fosdem => {
-                self.draw_icon(
-                    ((37000 - (time % 37000)) as f64 / 500.) as f32,
-                    0.,
-                    FOSDEM,
-                    vec![
-                        LED::black(),
-                        LED::from_hex("603030"),
-                        LED::from_hex("604030"),
-                        LED::from_hex("605030"),
-                        LED::from_hex("606030"),
-                        LED::from_hex("506030"),
-                        LED::from_hex("406030"),
-                    ],
-                );
-            }
             IconType::Co2 => {
                 let x =
                     self.leds.range.0 - 1 - ((time / 5000) % 5) as usize * self.leds.range.0 / 5;
@@ -129,16 +137,95 @@ impl Icon {
                     CO2,
                     vec![LED::black(), LED::from_hex("502020")],
                 );
-                self.leds.set_string(
+                self.leds.set_text(
                     x + 27,
                     // ((37000 - (time % 37000)) as usize * self.leds.range.0) / 37000,
                     &format!("{}", self.co2),
+                    LED::from_rgb(0xa0, 0xa0, 0xa0),
                 );
             }
+            icon => match Self::builtin(icon).or_else(|| self.registry.get(icon.name()).cloned()) {
+                Some(def) => self.draw_def(&def, time),
+                None => self.leds.clear(),
+            },
         }
         self.leds.leds.clone()
     }
 
+    // The icons drawn from a sprite, without code of their own.
+    fn builtin(icon: &IconType) -> Option<IconDef> {
+        let wobble = |x| Animation {
+            motion: Motion::Wobble {
+                x,
+                amplitude: 3.,
+                period_ms: 12566,
+            },
+            cycle_ms: None,
+        };
+        let warm = vec![
+            LED::black(),
+            LED::from_hex("603030"),
+            LED::from_hex("604030"),
+            LED::from_hex("605030"),
+            LED::from_hex("606030"),
+            LED::from_hex("506030"),
+            LED::from_hex("406030"),
+        ];
+        let still = Sprite::still;
+        let (sprite, palette, animation) = match icon {
+            IconType::Test => (
+                still(TEST),
+                vec![LED::black(), LED::from_hex("800000")],
+                wobble(40.),
+            ),
+            IconType::Pumpkin => (
+                still(PUMPKIN),
+                vec![LED::black(), LED::from_hex("402000")],
+                wobble(20.),
+            ),
+            IconType::Fish => (
+                still(FISH),
+                vec![LED::black(), LED::from_hex("204040")],
+                Animation::scroll(2.),
+            ),
+            IconType::BlackAlps => (
+                still(BLACKALPS),
+                vec![LED::black(), LED::from_hex("103030")],
+                Animation::scroll(2.),
+            ),
+            IconType::TiTi => (still(TITI), warm[..5].to_vec(), Animation::scroll(-2.)),
+            IconType::Fosdem => (still(FOSDEM), warm, Animation::scroll(-2.)),
+            IconType::Pacman => (
+                Sprite {
+                    frames: PACMAN.iter().map(|p| Frame::new(p, 100)).collect(),
+                    playback: Playback::PingPong,
+                },
+                vec![
+                    LED::black(),
+                    LED::from_hex("303010"),
+                    LED::from_hex("a05050"),
+                ],
+                Animation::scroll(5.),
+            ),
+            _ => return None,
+        };
+        Some(IconDef {
+            name: icon.name().into(),
+            sprite,
+            palette,
+            animation,
+        })
+    }
+
+    fn draw_def(&mut self, def: &IconDef, time: u128) {
+        self.draw_icon(
+            def.animation.motion.position(time, self.leds.range.0),
+            0.,
+            def.sprite.pattern(time),
+            def.animation.palette(&def.palette, time),
+        );
+    }
+
     pub fn get_icon(&self) -> IconType {
         self.icon.clone()
     }
@@ -185,6 +272,19 @@ const CO2: &str = r#"
 000000000000000000000000
 "#;
 
+const TEST: &str = r#"
+1
+1
+1
+1
+1
+1
+1
+1
+1
+1
+"#;
+
 const BLACKALPS: &str = r#"
 0000000000000011000000000000000000000000
 0000000000000111100000000000000000000000
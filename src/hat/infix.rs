@@ -0,0 +1,420 @@
+use crate::hat::program::{Op, Program, ProgramError};
+
+/// Maximum nesting of expressions, so that deep formulas cannot overflow
+/// the stack of the parser.
+const DEPTH_MAX: usize = 64;
+
+/// Compiles a formula in infix notation, like `sin(t + x) * 0.5`, into the
+/// same program as its reverse polish notation.
+///
+/// The grammar, from lowest to highest precedence:
+///
+/// expr    := or ('?' expr ':' expr)?
+/// or      := and ('or' and)*
+/// and     := compare ('and' compare)*
+/// compare := sum (('<' | '>' | '<=' | '>=' | '==') sum)*
+/// sum     := term (('+' | '-') term)*
+/// term    := unary (('*' | '/' | '%') unary)*
+/// unary   := ('-' | 'not') unary | power
+/// power   := primary ('^' unary)?
+/// primary := number | variable | name '(' expr (',' expr)* ')' | '(' expr ')'
+pub fn compile(raw: &str) -> Result<Program, ProgramError> {
+    let mut parser = Parser {
+        tokens: tokenize(raw)?,
+        pos: 0,
+        depth: 0,
+        prog: Program::default(),
+    };
+    if !parser.tokens.is_empty() {
+        parser.expr()?;
+        if let Some(token) = parser.peek() {
+            return Err(token.unexpected());
+        }
+    }
+    parser.prog.finish()
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Token<'a> {
+    index: usize,
+    text: &'a str,
+}
+
+impl Token<'_> {
+    fn unexpected(&self) -> ProgramError {
+        ProgramError::UnexpectedToken {
+            index: self.index,
+            token: self.text.into(),
+        }
+    }
+}
+
+// Splits the formula into numbers, names, and single-character symbols.
+fn tokenize(raw: &str) -> Result<Vec<Token<'_>>, ProgramError> {
+    let mut tokens = vec![];
+    let mut chars = raw.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        let mut end = start + c.len_utf8();
+        if c.is_ascii_alphanumeric() || c == '.' || c == '_' {
+            let number = c.is_ascii_digit() || c == '.';
+            while let Some(&(i, n)) = chars.peek() {
+                let part = if number {
+                    n.is_ascii_digit() || n == '.' || exponent(&raw[start..i], n)
+                } else {
+                    n.is_ascii_alphanumeric() || n == '_'
+                };
+                if !part {
+                    break;
+                }
+                end = i + n.len_utf8();
+                chars.next();
+            }
+        } else if "<>=".contains(c) {
+            // '<=', '>=', and '==' are the only symbols with two characters
+            if let Some((i, '=')) = chars.peek().copied() {
+                end = i + 1;
+                chars.next();
+            } else if c == '=' {
+                return Err(ProgramError::UnknownToken {
+                    index: tokens.len(),
+                    token: c.into(),
+                });
+            }
+        } else if !"+-*/%^(),?:".contains(c) {
+            return Err(ProgramError::UnknownToken {
+                index: tokens.len(),
+                token: c.into(),
+            });
+        }
+        tokens.push(Token {
+            index: tokens.len(),
+            text: &raw[start..end],
+        });
+    }
+    Ok(tokens)
+}
+
+// Whether the character continues a number as exponent, like in '1e-3'.
+fn exponent(number: &str, c: char) -> bool {
+    let last = number.chars().last().unwrap_or_default();
+    match c {
+        'e' | 'E' => !number.contains(['e', 'E']),
+        '+' | '-' => last == 'e' || last == 'E',
+        _ => false,
+    }
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+    // Expressions entered but not finished.
+    depth: usize,
+    prog: Program,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<Token<'a>> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<Token<'a>, ProgramError> {
+        let token = self.peek().ok_or(ProgramError::UnexpectedEnd)?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    // Consumes the next token if it is one of the given symbols.
+    fn accept(&mut self, symbols: &[&str]) -> Option<Token<'a>> {
+        let token = self.peek().filter(|t| symbols.contains(&t.text))?;
+        self.pos += 1;
+        Some(token)
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<Token<'a>, ProgramError> {
+        let token = self.next()?;
+        if token.text != symbol {
+            return Err(token.unexpected());
+        }
+        Ok(token)
+    }
+
+    fn emit(&mut self, token: Token, op: Op) -> Result<(), ProgramError> {
+        self.prog.push(token.index, token.text, op)
+    }
+
+    // Emits the operator of a binary symbol like '+'.
+    fn emit_symbol(&mut self, token: Token) -> Result<(), ProgramError> {
+        let op = Op::parse(token.text).ok_or_else(|| token.unexpected())?;
+        self.emit(token, op)
+    }
+
+    // Counts an expression entered by `expr` or `unary`, which all
+    // recursion goes through.
+    // The depth is not restored after an error, as parsing stops anyway.
+    fn enter(&mut self) -> Result<(), ProgramError> {
+        if self.depth == DEPTH_MAX {
+            let token = self.peek().ok_or(ProgramError::UnexpectedEnd)?;
+            return Err(ProgramError::TooDeep {
+                index: token.index,
+                token: token.text.into(),
+            });
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn expr(&mut self) -> Result<(), ProgramError> {
+        self.enter()?;
+        self.or()?;
+        if let Some(select) = self.accept(&["?"]) {
+            self.expr()?;
+            self.expect(":")?;
+            self.expr()?;
+            self.emit_symbol(select)?;
+        }
+        self.depth -= 1;
+        Ok(())
+    }
+
+    fn or(&mut self) -> Result<(), ProgramError> {
+        self.and()?;
+        while let Some(op) = self.accept(&["or"]) {
+            self.and()?;
+            self.emit_symbol(op)?;
+        }
+        Ok(())
+    }
+
+    fn and(&mut self) -> Result<(), ProgramError> {
+        self.compare()?;
+        while let Some(op) = self.accept(&["and"]) {
+            self.compare()?;
+            self.emit_symbol(op)?;
+        }
+        Ok(())
+    }
+
+    fn compare(&mut self) -> Result<(), ProgramError> {
+        self.sum()?;
+        while let Some(op) = self.accept(&["<", ">", "<=", ">=", "=="]) {
+            self.sum()?;
+            self.emit_symbol(op)?;
+        }
+        Ok(())
+    }
+
+    fn sum(&mut self) -> Result<(), ProgramError> {
+        self.term()?;
+        while let Some(op) = self.accept(&["+", "-"]) {
+            self.term()?;
+            self.emit_symbol(op)?;
+        }
+        Ok(())
+    }
+
+    fn term(&mut self) -> Result<(), ProgramError> {
+        self.unary()?;
+        while let Some(op) = self.accept(&["*", "/", "%"]) {
+            self.unary()?;
+            self.emit_symbol(op)?;
+        }
+        Ok(())
+    }
+
+    fn unary(&mut self) -> Result<(), ProgramError> {
+        self.enter()?;
+        if let Some(minus) = self.accept(&["-"]) {
+            self.unary()?;
+            self.emit(minus, Op::Neg)?;
+        } else if let Some(not) = self.accept(&["not"]) {
+            self.unary()?;
+            self.emit_symbol(not)?;
+        } else {
+            self.power()?;
+        }
+        self.depth -= 1;
+        Ok(())
+    }
+
+    fn power(&mut self) -> Result<(), ProgramError> {
+        self.primary()?;
+        if let Some(op) = self.accept(&["^"]) {
+            self.unary()?;
+            self.emit_symbol(op)?;
+        }
+        Ok(())
+    }
+
+    fn primary(&mut self) -> Result<(), ProgramError> {
+        let token = self.next()?;
+        if token.text == "(" {
+            self.expr()?;
+            self.expect(")")?;
+            return Ok(());
+        }
+        let first = token.text.chars().next().unwrap_or_default();
+        if !first.is_ascii_alphanumeric() && first != '.' || ["and", "or"].contains(&token.text) {
+            return Err(token.unexpected());
+        }
+        // Stack words like 'dup' only make sense in reverse polish notation.
+        let op = Op::parse(token.text)
+            .filter(|op| op.arity().1 == 1)
+            .ok_or_else(|| ProgramError::UnknownToken {
+                index: token.index,
+                token: token.text.into(),
+            })?;
+        let expected = op.arity().0;
+        if expected == 0 {
+            return self.emit(token, op);
+        }
+
+        self.expect("(")?;
+        let mut found = 0;
+        if self.accept(&[")"]).is_none() {
+            loop {
+                self.expr()?;
+                found += 1;
+                if self.accept(&[","]).is_none() {
+                    break;
+                }
+            }
+            self.expect(")")?;
+        }
+        if found != expected {
+            return Err(ProgramError::ArgumentCount {
+                index: token.index,
+                token: token.text.into(),
+                expected,
+                found,
+            });
+        }
+        self.emit(token, op)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hat::program::Syntax;
+
+    fn ops(raw: &str) -> Program {
+        compile(raw).unwrap()
+    }
+
+    fn rpn(raw: &str) -> Program {
+        Program::compile(raw, Syntax::Rpn).unwrap()
+    }
+
+    #[test]
+    fn test_same_as_rpn() {
+        assert_eq!(ops(""), rpn(""));
+        assert_eq!(ops("sin(t + x)"), rpn("t x + sin"));
+        assert_eq!(ops("1 + 2 * 3"), rpn("1 2 3 * +"));
+        assert_eq!(ops("(1 + 2) * 3"), rpn("1 2 + 3 *"));
+        assert_eq!(ops("1 - 2 - 3"), rpn("1 2 - 3 -"));
+        assert_eq!(ops("2 ^ 3 ^ 2"), rpn("2 3 2 ^ ^"));
+        assert_eq!(ops("-x ^ 2"), rpn("x 2 ^ neg"));
+        assert_eq!(ops("2 ^ -y"), rpn("2 y neg ^"));
+        assert_eq!(ops("pow(x, y) % abs(-0.5)"), rpn("x y pow 0.5 neg abs %"));
+        assert_eq!(ops("x + 1 < y * 2"), rpn("x 1 + y 2 * <"));
+        assert_eq!(ops("x <= y == 1"), rpn("x y <= 1 =="));
+        assert_eq!(
+            ops("x > 0 and not y >= 1 or t"),
+            rpn("x 0 > y not 1 >= and t or")
+        );
+        assert_eq!(ops("x < 0 ? -1 : y ? 2 : 3"), rpn("x 0 < 1 neg y 2 3 ? ?"));
+        assert_eq!(ops("(x ? y : t) * 2"), rpn("x y t ? 2 *"));
+        assert_eq!(ops("1e-3 * x + 2.5E2"), rpn("1e-3 x * 2.5E2 +"));
+        assert_eq!(ops("1e+2-x"), rpn("1e+2 x -"));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(compile("sin(x"), Err(ProgramError::UnexpectedEnd));
+        assert_eq!(
+            compile("x y"),
+            Err(ProgramError::UnexpectedToken {
+                index: 1,
+                token: "y".into()
+            })
+        );
+        assert_eq!(
+            compile("x + foo(y)"),
+            Err(ProgramError::UnknownToken {
+                index: 2,
+                token: "foo".into()
+            })
+        );
+        assert_eq!(
+            compile("dup(x)"),
+            Err(ProgramError::UnknownToken {
+                index: 0,
+                token: "dup".into()
+            })
+        );
+        assert_eq!(compile("x ? y"), Err(ProgramError::UnexpectedEnd));
+        assert_eq!(
+            compile("x = y"),
+            Err(ProgramError::UnknownToken {
+                index: 1,
+                token: "=".into()
+            })
+        );
+        assert_eq!(
+            compile("and(x, y)"),
+            Err(ProgramError::UnexpectedToken {
+                index: 0,
+                token: "and".into()
+            })
+        );
+        assert_eq!(
+            compile("x $ y"),
+            Err(ProgramError::UnknownToken {
+                index: 1,
+                token: "$".into()
+            })
+        );
+        assert_eq!(
+            compile("pow(x)"),
+            Err(ProgramError::ArgumentCount {
+                index: 0,
+                token: "pow".into(),
+                expected: 2,
+                found: 1
+            })
+        );
+        assert_eq!(
+            compile("x * inf"),
+            Err(ProgramError::NonFiniteConstant {
+                index: 2,
+                token: "inf".into()
+            })
+        );
+    }
+
+    #[test]
+    fn test_depth() {
+        let nested = |depth| format!("{}x{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(ops(&nested(20)), rpn("x"));
+        assert!(matches!(
+            compile(&nested(400)),
+            Err(ProgramError::TooDeep { token, .. }) if token == "("
+        ));
+        assert!(matches!(
+            compile(&format!("{}1", "-".repeat(400))),
+            Err(ProgramError::TooDeep { token, .. }) if token == "-"
+        ));
+        assert!(matches!(
+            compile(&format!("{}x{}", "sin(".repeat(200), ")".repeat(200))),
+            Err(ProgramError::TooDeep { token, .. }) if token == "sin"
+        ));
+        // Longer formulas are rejected before parsing
+        assert_eq!(
+            Program::compile(&"-".repeat(200_000), Syntax::Infix),
+            Err(ProgramError::TooLong { length: 200_000 })
+        );
+    }
+}
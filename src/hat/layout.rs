@@ -0,0 +1,209 @@
+/// Maps the LEDs of a wearable to the grid where the pictures are drawn.
+/// The grid has x going around or along the wearable, and y going up, and
+/// not every position of the grid needs to have an LED.
+pub trait Layout: Send + Sync {
+    /// Number of LEDs.
+    fn len(&self) -> usize;
+
+    /// Number of columns and rows of the grid.
+    fn size(&self) -> (usize, usize);
+
+    /// The position of the LED on the grid.
+    fn position(&self, index: usize) -> (usize, usize);
+
+    /// The LED at this position of the grid, if there is one.
+    /// Positions outside of the grid continue the way the LEDs do, so on a
+    /// spiral a column after the last one is on the next turn.
+    fn index(&self, x: i64, y: i64) -> Option<usize>;
+
+    /// The position of the LED with x from 0 to 1 along the columns, and y
+    /// from 0 at the bottom to 1 at the top.
+    /// Scaled by the size of the grid minus one, [Layout::index] gives the
+    /// LED back.
+    fn normalized(&self, index: usize) -> (f32, f32) {
+        let (width, height) = self.size();
+        let (x, y) = self.position(index);
+        (
+            x as f32 / (width.max(2) - 1) as f32,
+            y as f32 / (height.max(2) - 1) as f32,
+        )
+    }
+}
+
+/// The LEDs of the hat, spiralling from the bottom to the top.
+/// When they are interleaved, every turn is shifted by half an LED, so
+/// the grid has twice the circumference, with every second position empty:
+///
+/// X  .  X  .  X
+/// .  X  .  X  .
+/// X  .  X  .  X
+pub struct Spiral {
+    pub leds: usize,
+    // Number of LEDs in one turn.
+    pub circumference: usize,
+    pub interleaved: bool,
+}
+
+impl Spiral {
+    // Positions of the grid for each LED.
+    fn stride(&self) -> usize {
+        if self.interleaved {
+            2
+        } else {
+            1
+        }
+    }
+}
+
+impl Layout for Spiral {
+    fn len(&self) -> usize {
+        self.leds
+    }
+
+    fn size(&self) -> (usize, usize) {
+        let width = self.circumference * self.stride() - (self.stride() - 1);
+        (width, (self.leds * self.stride()).div_ceil(width))
+    }
+
+    fn position(&self, index: usize) -> (usize, usize) {
+        let pos = index * self.stride();
+        (pos % self.size().0, pos / self.size().0)
+    }
+
+    fn index(&self, x: i64, y: i64) -> Option<usize> {
+        let pos = y * self.size().0 as i64 + x;
+        let stride = self.stride() as i64;
+        (pos >= 0 && pos % stride == 0 && pos / stride < self.leds as i64)
+            .then_some((pos / stride) as usize)
+    }
+}
+
+/// A flat matrix of LEDs, wired row by row from the bottom left.
+pub struct Matrix {
+    pub leds: usize,
+    pub width: usize,
+    // Every second row is wired from right to left.
+    pub serpentine: bool,
+}
+
+impl Layout for Matrix {
+    fn len(&self) -> usize {
+        self.leds
+    }
+
+    fn size(&self) -> (usize, usize) {
+        (self.width, self.leds.div_ceil(self.width))
+    }
+
+    fn position(&self, index: usize) -> (usize, usize) {
+        let (x, y) = (index % self.width, index / self.width);
+        match self.serpentine && y % 2 == 1 {
+            true => (self.width - 1 - x, y),
+            false => (x, y),
+        }
+    }
+
+    fn index(&self, x: i64, y: i64) -> Option<usize> {
+        let (width, height) = self.size();
+        if x < 0 || y < 0 || x >= width as i64 || y >= height as i64 {
+            return None;
+        }
+        let (x, y) = (x as usize, y as usize);
+        let x = match self.serpentine && y % 2 == 1 {
+            true => width - 1 - x,
+            false => x,
+        };
+        Some(y * width + x).filter(|&index| index < self.leds)
+    }
+}
+
+/// A single row of LEDs going around, like a bracelet.
+pub struct Ring {
+    pub leds: usize,
+}
+
+impl Layout for Ring {
+    fn len(&self) -> usize {
+        self.leds
+    }
+
+    fn size(&self) -> (usize, usize) {
+        (self.leds, 1)
+    }
+
+    fn position(&self, index: usize) -> (usize, usize) {
+        (index, 0)
+    }
+
+    fn index(&self, x: i64, y: i64) -> Option<usize> {
+        (y == 0).then_some(x.rem_euclid(self.leds as i64) as usize)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Every LED is at a position which maps back to it.
+    fn check_roundtrip(layout: &dyn Layout) {
+        for index in 0..layout.len() {
+            let (x, y) = layout.position(index);
+            assert_eq!(layout.index(x as i64, y as i64), Some(index));
+            let (nx, ny) = layout.normalized(index);
+            assert!((0. ..=1.).contains(&nx) && (0. ..=1.).contains(&ny));
+            let (width, height) = layout.size();
+            let x = (nx * (width.max(2) - 1) as f32).round() as i64;
+            let y = (ny * (height.max(2) - 1) as f32).round() as i64;
+            assert_eq!(layout.index(x, y), Some(index));
+        }
+    }
+
+    #[test]
+    fn test_spiral() {
+        let hat = Spiral {
+            leds: 300,
+            circumference: 37,
+            interleaved: true,
+        };
+        assert_eq!(hat.size(), (73, 9));
+        assert_eq!(hat.position(37), (1, 1));
+        assert_eq!(hat.index(0, 1), None);
+        // Going left of the first column is the end of the previous turn
+        assert_eq!(hat.index(-1, 1), Some(36));
+        check_roundtrip(&hat);
+
+        let plain = Spiral {
+            leds: 6,
+            circumference: 3,
+            interleaved: false,
+        };
+        assert_eq!(plain.size(), (3, 2));
+        assert_eq!(plain.index(3, 0), Some(3));
+        check_roundtrip(&plain);
+    }
+
+    #[test]
+    fn test_matrix() {
+        let mut matrix = Matrix {
+            leds: 6,
+            width: 3,
+            serpentine: false,
+        };
+        assert_eq!(matrix.position(3), (0, 1));
+        assert_eq!(matrix.index(3, 0), None);
+        check_roundtrip(&matrix);
+
+        matrix.serpentine = true;
+        assert_eq!(matrix.position(3), (2, 1));
+        check_roundtrip(&matrix);
+    }
+
+    #[test]
+    fn test_ring() {
+        let ring = Ring { leds: 12 };
+        assert_eq!(ring.size(), (12, 1));
+        assert_eq!(ring.index(-1, 0), Some(11));
+        assert_eq!(ring.index(0, 1), None);
+        check_roundtrip(&ring);
+    }
+}
@@ -1,49 +1,44 @@
-use std::f32::consts::PI;
+use std::{f32::consts::PI, sync::Arc};
 
-/// Handles diagonal LED arrangements, like this:
+use serde::{Deserialize, Serialize};
+
+use crate::hat::{font, geometry::Geometry, layout::Layout};
+
+/// Draws on the grid of a [Layout], which can have holes, like the
+/// diagonal LED arrangement of the hat:
 ///
 /// X  .  X  .  X
 /// .  X  .  X  .
 /// X  .  X  .  X
-///
-/// Also supposes that the LEDs create a spiral.
 pub struct LEDCriss {
-    // The total is the number of LEDs, without the holes.
-    total: usize,
-    // The circumference contains the holes in the pattern.
-    circum: usize,
-    // The available range as (width, height), where only half
-    // of the LEDs are actually populated.
+    layout: Arc<dyn Layout>,
+    // The available range as (width, height), where not all positions
+    // need to have an LED.
     pub range: (usize, usize),
-    // LEDs are spiralling around from bottom to top,
-    // so the last LED with y = 0 is left of the first LED with y = 1.
+    // LEDs in the order of the layout, so on the spiral of the hat
+    // the last LED with y = 0 is left of the first LED with y = 1.
     pub leds: Vec<LED>,
 }
 
 impl LEDCriss {
-    /// total number of LEds, and circum number of LEDs on the bottom range.
-    pub fn new(total: usize, circum: usize) -> Self {
-        let range = (
-            circum * 2 - 1,
-            (total as f32 / circum as f32).ceil() as usize,
-        );
+    pub fn new(geometry: Geometry) -> Self {
+        let layout = geometry.layout();
         Self {
-            total,
-            circum: range.0,
-            range,
-            leds: vec![LED::black(); total],
+            range: layout.size(),
+            leds: vec![LED::black(); layout.len()],
+            layout,
         }
     }
 
     pub fn clear(&mut self) {
-        self.leds = vec![LED::black(); self.total];
+        self.leds = vec![LED::black(); self.layout.len()];
     }
 
     // Perhaps a bit too optimized.
     // It applies a filter to the LEDs with the following properties:
     // - if (fx, fy) falls on a _real_ LED, the LED is set with that color
     // - the sum of all intensities for each color corresponds to the [led] color
-    // - it wraps around if the object is with x < 0 or x > circum
+    // - it continues like the layout if the object is with x < 0 or x > width
     pub fn set(&mut self, fx: f32, fy: f32, led: &LED) {
         // println!("fx({fx}) - fy({fy})");
         // The actual grid as reference
@@ -53,16 +48,10 @@ impl LEDCriss {
             for ly in -1..=2 {
                 let (x, y) = (ix + lx, iy + ly);
                 // println!("lx({lx}) - ly({ly}) - x({x}) - y({y})");
-                if (x + y) % 2 != 0 {
-                    // Not on a real LED, but on a hole.
-                    // println!("LED hole");
-                    continue;
-                }
-                let index = (y * self.circum as i32) + x;
-                if index < 0 || index / 2 >= self.total as i32 {
-                    // println!("Index out of range: index({index})");
+                let Some(index) = self.layout.index(x as i64, y as i64) else {
+                    // Not on a real LED, but on a hole or out of range.
                     continue;
-                }
+                };
 
                 let (dx, dy) = (x as f32 - fx, y as f32 - fy);
                 let bright = Self::calc_square(dx, dy);
@@ -70,7 +59,7 @@ impl LEDCriss {
                 // Add to existing LEDs, so it should be nice.
                 // println!("index({index}) - dx({dx})/dy({dy}) - bright({bright})");
                 if bright >= 0. {
-                    self.leds[index as usize / 2].add(&led.brightness(bright));
+                    self.leds[index].add(&led.brightness(bright));
                 } else {
                     // println!("Brightness negative");
                 }
@@ -79,9 +68,9 @@ impl LEDCriss {
     }
 
     pub fn set_u(&mut self, x: usize, y: usize, led: LED) {
-        let index = (y * self.circum) + x % self.circum;
-        if index % 2 == 0 {
-            self.leds[index / 2] = led;
+        let x = x % self.range.0;
+        if let Some(index) = self.layout.index(x as i64, y as i64) {
+            self.leds[index] = led;
         }
     }
 
@@ -97,30 +86,33 @@ impl LEDCriss {
         }
     }
 
-    pub fn set_char(&mut self, x: usize, char: u8) {
-        match char {
-            b'0'..=b'9' => self.write_char(x, DIGITS[(char - b'0') as usize]),
-            _ => {
-                tracing::warn!("Printing unknown char: {char}")
+    /// Draws the 8x8 bitmap of a character with its left edge at x.
+    pub fn set_glyph(&mut self, x: usize, c: char, led: LED) {
+        for (y, row) in font::glyph(c).iter().rev().enumerate() {
+            for b in 0..8 {
+                if (row >> b) & 1 == 1 {
+                    self.set_u(x + b, y, led);
+                }
             }
         }
     }
 
-    fn write_char(&mut self, x: usize, bit: [u8; DIGITS_WIDTH]) {
-        let on = LED::from_rgb(0xa0, 0xa0, 0xa0);
-        for y in 0..8 {
-            let byte = bit[7 - y];
-            for b in 0..DIGITS_WIDTH {
-                if (byte >> b) & 1 == 1 {
-                    self.set_u(x + b, y, on.clone());
-                }
-            }
+    /// Writes a text with proportional widths, starting at x, and returns
+    /// its width.
+    pub fn set_text(&mut self, x: usize, text: &str, led: LED) -> usize {
+        let columns = font::columns(text);
+        for (i, column) in columns.iter().enumerate() {
+            self.set_column(x + i, *column, led);
         }
+        columns.len()
     }
 
-    pub fn set_string(&mut self, x: usize, s: &str) {
-        for c in s.as_bytes().iter().enumerate() {
-            self.set_char(x + c.0 * DIGITS_WIDTH, *c.1);
+    /// Lights the LEDs of one column of [font::columns].
+    pub fn set_column(&mut self, x: usize, column: u8, led: LED) {
+        for y in 0..font::HEIGHT {
+            if (column >> (font::HEIGHT - 1 - y)) & 1 == 1 {
+                self.set_u(x, y, led);
+            }
         }
     }
 
@@ -149,7 +141,72 @@ impl LEDCriss {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// How the three values of a formula are turned into a colour.
+/// All values are expected between 0 and 1, the hue wraps around.
+/// html/js/color-model.js mirrors these conversions for the simulation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorModel {
+    #[default]
+    Rgb,
+    Hsv,
+    Hsl,
+}
+
+impl ColorModel {
+    pub fn to_led(self, a: f32, b: f32, c: f32) -> LED {
+        match self {
+            ColorModel::Rgb => LED::from_rgb(LED::to_byte(a), LED::to_byte(b), LED::to_byte(c)),
+            ColorModel::Hsv => LED::from_hsv(a, b, c),
+            ColorModel::Hsl => LED::from_hsl(a, b, c),
+        }
+    }
+}
+
+/// How formula results end up on the LEDs: the range of formula values
+/// shown, the brightest channel value, and a gamma curve.
+/// html/js/color-model.js mirrors this mapping for the simulation.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct OutputMapping {
+    // Formula values mapped to the darkest and the brightest channel value.
+    pub input_min: f32,
+    pub input_max: f32,
+    // Brightest value sent to a channel.
+    pub max_value: u8,
+    // Exponent applied to each channel, 1 is linear, 2.2 makes the
+    // perceived brightness linear.
+    pub gamma: f32,
+}
+
+impl Default for OutputMapping {
+    fn default() -> Self {
+        Self {
+            input_min: -1.,
+            input_max: 1.,
+            max_value: 63,
+            gamma: 1.,
+        }
+    }
+}
+
+impl OutputMapping {
+    pub fn is_valid(&self) -> bool {
+        self.input_min.is_finite()
+            && self.input_max.is_finite()
+            && self.input_min != self.input_max
+            && self.gamma.is_finite()
+            && self.gamma > 0.
+    }
+
+    /// Maps a formula value to the 0..1 range of the colour models.
+    pub fn to_unit(self, value: f32) -> f32 {
+        (value - self.input_min) / (self.input_max - self.input_min)
+    }
+}
+
+/// Serialized as hex, like "ff8000".
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
 pub struct LED {
     red: u8,
     green: u8,
@@ -195,6 +252,51 @@ impl LED {
         self.blue
     }
 
+    /// Hue wraps around, saturation and value are clamped to 0..1.
+    pub fn from_hsv(hue: f32, saturation: f32, value: f32) -> LED {
+        let (s, v) = (saturation.clamp(0., 1.), value.clamp(0., 1.));
+        let [red, green, blue] = Self::hue_rgb(hue).map(|c| Self::to_byte(v * (1. - s + s * c)));
+        Self { red, green, blue }
+    }
+
+    /// Hue wraps around, saturation and lightness are clamped to 0..1.
+    pub fn from_hsl(hue: f32, saturation: f32, lightness: f32) -> LED {
+        let (s, l) = (saturation.clamp(0., 1.), lightness.clamp(0., 1.));
+        let chroma = (1. - (2. * l - 1.).abs()) * s;
+        let [red, green, blue] = Self::hue_rgb(hue).map(|c| Self::to_byte(l + chroma * (c - 0.5)));
+        Self { red, green, blue }
+    }
+
+    // The fully saturated colour of this hue, with 0 being red, 1/3 green
+    // and 2/3 blue.
+    fn hue_rgb(hue: f32) -> [f32; 3] {
+        let h = (hue - hue.floor()) * 6.;
+        [
+            ((h - 3.).abs() - 1.).clamp(0., 1.),
+            (2. - (h - 2.).abs()).clamp(0., 1.),
+            (2. - (h - 4.).abs()).clamp(0., 1.),
+        ]
+    }
+
+    // Maps 0..1 to 0..255.
+    fn to_byte(c: f32) -> u8 {
+        (c * 256.).clamp(0., 255.) as u8
+    }
+
+    /// Applies the gamma curve and scales the channels to the maximum
+    /// value of the mapping.
+    pub fn map_output(&self, mapping: &OutputMapping) -> LED {
+        let map = |c: u8| {
+            let v = (c as f32 / 256.).powf(mapping.gamma) * (mapping.max_value as f32 + 1.);
+            (v as u8).min(mapping.max_value)
+        };
+        Self {
+            red: map(self.red),
+            green: map(self.green),
+            blue: map(self.blue),
+        }
+    }
+
     pub fn _from_hue(hue: u8) -> LED {
         let bright = hue % 64;
         let hue = hue / 64;
@@ -239,6 +341,14 @@ impl LED {
         l
     }
 
+    /// Like [LED::from_hex], but fails on anything else than six hex digits.
+    pub fn try_from_hex(hex: &str) -> Option<LED> {
+        if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(Self::from_hex(hex))
+    }
+
     pub fn _is_black(&self) -> bool {
         self.red == 0 && self.green == 0 && self.blue == 0
     }
@@ -251,6 +361,16 @@ impl LED {
         }
     }
 
+    /// Blends towards `other`: 0 keeps this LED, 1 gives `other`.
+    pub fn mix(&self, other: &LED, ratio: f32) -> LED {
+        let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * ratio).round() as u8;
+        Self {
+            red: mix(self.red, other.red),
+            green: mix(self.green, other.green),
+            blue: mix(self.blue, other.blue),
+        }
+    }
+
     pub fn _mean(&self, others: Vec<Self>) -> Self {
         let (mut red, mut green, mut blue) =
             (self.red as usize, self.green as usize, self.blue as usize);
@@ -289,6 +409,20 @@ impl LED {
     }
 }
 
+impl TryFrom<String> for LED {
+    type Error = String;
+
+    fn try_from(hex: String) -> Result<Self, Self::Error> {
+        Self::try_from_hex(&hex).ok_or_else(|| format!("'{hex}' is not a colour like 'ff8000'"))
+    }
+}
+
+impl From<LED> for String {
+    fn from(led: LED) -> Self {
+        led.to_string()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -313,7 +447,7 @@ mod test {
     // . 04 . 05 . 06 .
     // 00 . 01 . 02 . 03
     fn test_led_integer() {
-        let mut leds = LEDCriss::new(15, 4);
+        let mut leds = LEDCriss::new(Geometry::new(15, 4));
         let w = &LED::_white();
         leds.set(1., 1., w);
         check_leds(&leds, "0000 f00 0000 000 0");
@@ -339,6 +473,63 @@ mod test {
         check_leds(&leds, "0000 000 0000 000 0");
     }
 
+    #[test]
+    fn test_color_model() {
+        let rgb = |led: LED| (led.red, led.green, led.blue);
+        assert_eq!(rgb(ColorModel::Rgb.to_led(0., 0.5, 2.)), (0, 128, 255));
+
+        assert_eq!(rgb(ColorModel::Hsv.to_led(0., 1., 1.)), (255, 0, 0));
+        assert_eq!(rgb(ColorModel::Hsv.to_led(1. / 3., 1., 1.)), (0, 255, 0));
+        assert_eq!(rgb(ColorModel::Hsv.to_led(-1. / 3., 1., 1.)), (0, 0, 255));
+        assert_eq!(rgb(ColorModel::Hsv.to_led(0.5, 0., 0.5)), (128, 128, 128));
+        assert_eq!(rgb(ColorModel::Hsv.to_led(0.5, 1., 0.)), (0, 0, 0));
+
+        assert_eq!(rgb(ColorModel::Hsl.to_led(0., 1., 0.5)), (255, 0, 0));
+        assert_eq!(rgb(ColorModel::Hsl.to_led(1. / 6., 1., 0.5)), (255, 255, 0));
+        assert_eq!(rgb(ColorModel::Hsl.to_led(0.5, 1., 1.)), (255, 255, 255));
+        assert_eq!(rgb(ColorModel::Hsl.to_led(0.5, 1., 0.)), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_output_mapping() {
+        let rgb = |led: LED| (led.red, led.green, led.blue);
+        let led = LED::from_rgb(0, 128, 255);
+        let mut mapping = OutputMapping::default();
+        assert_eq!(rgb(led.map_output(&mapping)), (0, 32, 63));
+        assert_eq!(mapping.to_unit(0.), 0.5);
+
+        mapping.max_value = 255;
+        assert_eq!(rgb(led.map_output(&mapping)), (0, 128, 255));
+        mapping.gamma = 2.;
+        assert_eq!(rgb(led.map_output(&mapping)), (0, 64, 254));
+
+        mapping.input_min = 0.;
+        assert_eq!(mapping.to_unit(0.5), 0.5);
+        assert!(mapping.is_valid());
+        mapping.input_max = 0.;
+        assert!(!mapping.is_valid());
+    }
+
+    #[test]
+    fn test_hex() {
+        let led = LED::try_from_hex("0a80Ff").unwrap();
+        assert_eq!((led.red, led.green, led.blue), (10, 128, 255));
+        assert_eq!(serde_json::to_string(&led).unwrap(), r#""0a80ff""#);
+        assert_eq!(serde_json::from_str::<LED>(r#""0a80ff""#).unwrap(), led);
+        assert!(LED::try_from_hex("0a80f").is_none());
+        assert!(LED::try_from_hex("0a80fg").is_none());
+        assert!(serde_json::from_str::<LED>(r#""+a80ff""#).is_err());
+    }
+
+    #[test]
+    fn test_mix() {
+        let rgb = |led: LED| (led.red, led.green, led.blue);
+        let (a, b) = (LED::from_rgb(0, 100, 255), LED::from_rgb(255, 0, 255));
+        assert_eq!(rgb(a.mix(&b, 0.)), (0, 100, 255));
+        assert_eq!(rgb(a.mix(&b, 0.5)), (128, 50, 255));
+        assert_eq!(rgb(a.mix(&b, 1.)), (255, 0, 255));
+    }
+
     #[test]
     // 14
     // . 11 . 12 . 13 .
@@ -346,7 +537,7 @@ mod test {
     // . 04 . 05 . 06 .
     // 00 . 01 . 02 . 03
     fn test_led_float() {
-        let mut leds = LEDCriss::new(15, 4);
+        let mut leds = LEDCriss::new(Geometry::new(15, 4));
         let w = &LED::_white();
         leds.set(2.1, 2.1, w);
         check_leds(&leds, "0000 000 0e00 010 0");
@@ -360,34 +551,3 @@ mod test {
         check_leds(&leds, "0000 000 0060 060 0");
     }
 }
-
-// Copied from https://github.com/dhepper/font8x8/blob/master/font8x8_basic.h
-const DIGITS_WIDTH: usize = 8;
-const DIGITS: [[u8; 8]; 11] = [
-    [0x3E, 0x63, 0x73, 0x7B, 0x6F, 0x67, 0x3E, 0x00], // U+0030 (0)
-    [0x0C, 0x0E, 0x0C, 0x0C, 0x0C, 0x0C, 0x3F, 0x00], // U+0031 (1)
-    [0x1E, 0x33, 0x30, 0x1C, 0x06, 0x33, 0x3F, 0x00], // U+0032 (2)
-    [0x1E, 0x33, 0x30, 0x1C, 0x30, 0x33, 0x1E, 0x00], // U+0033 (3)
-    [0x38, 0x3C, 0x36, 0x33, 0x7F, 0x30, 0x78, 0x00], // U+0034 (4)
-    [0x3F, 0x03, 0x1F, 0x30, 0x30, 0x33, 0x1E, 0x00], // U+0035 (5)
-    [0x1C, 0x06, 0x03, 0x1F, 0x33, 0x33, 0x1E, 0x00], // U+0036 (6)
-    [0x3F, 0x33, 0x30, 0x18, 0x0C, 0x0C, 0x0C, 0x00], // U+0037 (7)
-    [0x1E, 0x33, 0x33, 0x1E, 0x33, 0x33, 0x1E, 0x00], // U+0038 (8)
-    [0x1E, 0x33, 0x33, 0x3E, 0x30, 0x18, 0x0E, 0x00], // U+0039 (9)
-    [0x00, 0x0C, 0x0C, 0x00, 0x00, 0x0C, 0x0C, 0x00], // U+003A (:)
-];
-
-// const DIGITS_WIDTH: usize = 5;
-// const DIGITS: [[u8; 8]; 11] = [
-//     [0x00, 0x1E, 0x33, 0x33, 0x33, 0x33, 0x33, 0x1E],
-//     [0x00, 0x18, 0x1C, 0x1A, 0x18, 0x18, 0x18, 0x18],
-//     [0x00, 0x1F, 0x33, 0x38, 0x1C, 0x0E, 0x07, 0x3F],
-//     [0x00, 0x3F, 0x18, 0x0C, 0x1E, 0x30, 0x33, 0x1F],
-//     [0x00, 0x18, 0x1C, 0x1A, 0x19, 0x7F, 0x18, 0x18],
-//     [0x00, 0x3F, 0x03, 0x1F, 0x30, 0x30, 0x33, 0x1F],
-//     [0x00, 0x1E, 0x03, 0x1F, 0x33, 0x33, 0x33, 0x1E],
-//     [0x00, 0x3F, 0x30, 0x38, 0x1C, 0x0E, 0x06, 0x06],
-//     [0x00, 0x1E, 0x33, 0x33, 0x1E, 0x33, 0x33, 0x1E],
-//     [0x00, 0x1E, 0x33, 0x33, 0x33, 0x3E, 0x30, 0x1E],
-//     [0x00, 0x00, 0x0C, 0x0C, 0x00, 0x00, 0x0C, 0x0C],
-// ];
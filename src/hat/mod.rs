@@ -1,7 +1,20 @@
 use crate::hat::leds::LED;
 
 pub mod countdown;
+pub mod font;
 pub mod function;
+pub mod geometry;
+pub mod history;
 pub mod icon;
+pub mod infix;
+pub mod layout;
 pub mod leds;
+pub mod motion;
+pub mod playlist;
+pub mod program;
+pub mod registry;
+pub mod sprite;
 pub mod switch;
+pub mod telemetry;
+pub mod text;
+pub mod transition;
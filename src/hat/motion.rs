@@ -0,0 +1,174 @@
+use std::f64::consts::TAU;
+
+use serde::{Deserialize, Serialize};
+
+use crate::hat::leds::LED;
+
+/// Maximum speed of an icon, in columns per second.
+pub const SPEED_MAX: f32 = 100.;
+
+/// How an icon moves around the hat, with positions in columns.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Motion {
+    Static {
+        x: f32,
+    },
+    // Columns per second, negative values scroll the other way.
+    Scroll {
+        speed: f32,
+    },
+    // Goes back and forth between two columns.
+    Bounce {
+        from: f32,
+        to: f32,
+        speed: f32,
+    },
+    // Swings around a column.
+    Wobble {
+        x: f32,
+        amplitude: f32,
+        period_ms: u64,
+    },
+}
+
+impl Motion {
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            Motion::Static { x } => x.is_finite(),
+            Motion::Scroll { speed } => speed.is_finite() && speed.abs() <= SPEED_MAX,
+            Motion::Bounce { from, to, speed } => {
+                from.is_finite() && to.is_finite() && speed.is_finite() && speed.abs() <= SPEED_MAX
+            }
+            Motion::Wobble {
+                x,
+                amplitude,
+                period_ms,
+            } => x.is_finite() && amplitude.is_finite() && period_ms > 0,
+        }
+    }
+
+    /// The column of the icon at the given time, between 0 and `width`.
+    // Computed with f64, as the time in ms since the epoch doesn't fit
+    // in an f32.
+    pub fn position(&self, time_ms: u128, width: usize) -> f32 {
+        let seconds = time_ms as f64 / 1000.;
+        let x = match *self {
+            Motion::Static { x } => x as f64,
+            Motion::Scroll { speed } => seconds * speed as f64,
+            Motion::Bounce { from, to, speed } => {
+                let span = (to - from).abs() as f64;
+                let travel = (seconds * speed.abs() as f64).rem_euclid(2. * span.max(f64::EPSILON));
+                let offset = if travel < span {
+                    travel
+                } else {
+                    2. * span - travel
+                };
+                from.min(to) as f64 + offset
+            }
+            Motion::Wobble {
+                x,
+                amplitude,
+                period_ms,
+            } => {
+                let phase = (time_ms % period_ms as u128) as f64 / period_ms as f64;
+                x as f64 + (phase * TAU).sin() * amplitude as f64
+            }
+        };
+        x.rem_euclid(width as f64) as f32
+    }
+}
+
+/// The motion of an icon, and how often its colours change.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct Animation {
+    pub motion: Motion,
+    // Every period, the colours of the palette move by one, except for
+    // the first one, which is the background.
+    #[serde(default)]
+    pub cycle_ms: Option<u64>,
+}
+
+impl Animation {
+    pub fn scroll(speed: f32) -> Self {
+        Self {
+            motion: Motion::Scroll { speed },
+            cycle_ms: None,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.motion.is_valid() && self.cycle_ms != Some(0)
+    }
+
+    /// The palette with its colours cycled to the given time.
+    pub fn palette(&self, palette: &[LED], time_ms: u128) -> Vec<LED> {
+        let mut palette = palette.to_vec();
+        if let Some(cycle_ms) = self.cycle_ms.filter(|_| palette.len() > 2) {
+            let shift = (time_ms / cycle_ms as u128) % (palette.len() - 1) as u128;
+            palette[1..].rotate_right(shift as usize);
+        }
+        palette
+    }
+}
+
+impl Default for Animation {
+    // Swims around the hat like the fish.
+    fn default() -> Self {
+        Self::scroll(2.)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_motion() {
+        let scroll = Motion::Scroll { speed: -2. };
+        assert_eq!(scroll.position(0, 73), 0.);
+        assert_eq!(scroll.position(1000, 73), 71.);
+
+        let bounce = Motion::Bounce {
+            from: 10.,
+            to: 20.,
+            speed: 5.,
+        };
+        assert_eq!(bounce.position(1000, 73), 15.);
+        assert_eq!(bounce.position(3000, 73), 15.);
+        assert_eq!(bounce.position(4000, 73), 10.);
+
+        let wobble = Motion::Wobble {
+            x: 40.,
+            amplitude: 3.,
+            period_ms: 4000,
+        };
+        assert_eq!(wobble.position(1000, 73), 43.);
+        assert_eq!(Motion::Static { x: 80. }.position(1000, 73), 7.);
+
+        assert!(!Motion::Scroll { speed: f32::NAN }.is_valid());
+        assert!(!Motion::Wobble {
+            x: 0.,
+            amplitude: 1.,
+            period_ms: 0
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn test_cycle() {
+        let palette = [
+            LED::black(),
+            LED::from_hex("100000"),
+            LED::from_hex("200000"),
+        ];
+        let animation = Animation {
+            cycle_ms: Some(100),
+            ..Animation::default()
+        };
+        assert_eq!(animation.palette(&palette, 50), palette);
+        let cycled = animation.palette(&palette, 150);
+        assert_eq!(cycled, [palette[0], palette[2], palette[1]]);
+        assert_eq!(animation.palette(&palette, 250), palette);
+    }
+}
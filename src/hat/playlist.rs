@@ -0,0 +1,330 @@
+use serde::{Deserialize, Serialize};
+
+use crate::hat::icon::IconType;
+
+/// Maximum number of steps in a playlist.
+pub const STEPS_MAX: usize = 100;
+
+/// What the hat shows during one step of a playlist.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum Scene {
+    Icon(IconType),
+    // Counts down to the end of the step.
+    Countdown,
+    // Lets everybody send formulas.
+    Function,
+}
+
+/// When a step of the playlist ends.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum Until {
+    // Time after the start of the step, in ms.
+    Duration(u128),
+    // Wall-clock time, in ms since the epoch.
+    Time(u128),
+    // Only ends when the admin does something else.
+    Forever,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Step {
+    pub scene: Scene,
+    pub until: Until,
+}
+
+/// A sequence of scenes uploaded by the admin.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Playlist {
+    pub steps: Vec<Step>,
+    // Starts over with the first step after the last one.
+    #[serde(default)]
+    pub repeat: bool,
+}
+
+impl Playlist {
+    /// A repeating playlist needs at least one step which doesn't end at a
+    /// wall-clock time, else it would skip all steps after the first round.
+    pub fn is_valid(&self) -> bool {
+        let repeats = self
+            .steps
+            .iter()
+            .any(|step| !matches!(step.until, Until::Time(_)));
+        !self.steps.is_empty()
+            && (!self.repeat || repeats)
+            && self.steps.len() <= STEPS_MAX
+            && self.steps.iter().all(|step| match step.until {
+                Until::Duration(ms) => ms > 0,
+                Until::Time(_) => true,
+                Until::Forever => step.scene != Scene::Countdown,
+            })
+    }
+
+    /// The icons shown by the playlist.
+    pub fn icons(&self) -> impl Iterator<Item = &IconType> {
+        self.steps.iter().filter_map(|step| match &step.scene {
+            Scene::Icon(icon) => Some(icon),
+            _ => None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlayerState {
+    Stopped,
+    Running,
+    Paused,
+}
+
+/// What the admin sees of the playlist.
+#[derive(Debug, Serialize)]
+pub struct PlaylistStatus {
+    pub state: PlayerState,
+    // The step currently shown, or the one to resume.
+    pub step: Option<usize>,
+    // End of the current step, in ms since the epoch, if known.
+    pub step_end_ms: Option<u128>,
+    pub playlist: Playlist,
+}
+
+/// Goes through the steps of a playlist as the time passes.
+/// The scenes are shown by the caller, see [Player::tick].
+#[derive(Default)]
+pub struct Player {
+    playlist: Playlist,
+    state: Option<Position>,
+}
+
+// Where the player is in the playlist.
+#[derive(Debug, Clone, Copy)]
+struct Position {
+    step: usize,
+    // Start of the step, in ms, or the time already spent in the step
+    // while paused.
+    time: u128,
+    paused: bool,
+    // The scene of this step has not been shown yet.
+    cue: bool,
+}
+
+impl Player {
+    /// Replaces the playlist, which is stopped until started again.
+    pub fn upload(&mut self, playlist: Playlist) {
+        self.playlist = playlist;
+        self.state = None;
+    }
+
+    /// Starts the playlist from the beginning, or resumes it if paused.
+    pub fn start(&mut self, time_ms: u128) {
+        self.state = Some(match self.state {
+            Some(pos) if pos.paused => Position {
+                time: time_ms.saturating_sub(pos.time),
+                paused: false,
+                cue: true,
+                ..pos
+            },
+            _ => Position {
+                step: 0,
+                time: time_ms,
+                paused: false,
+                cue: true,
+            },
+        });
+    }
+
+    /// Keeps the current step until the playlist is started again.
+    pub fn pause(&mut self, time_ms: u128) {
+        if let Some(pos) = self.state.as_mut().filter(|pos| !pos.paused) {
+            pos.time = time_ms.saturating_sub(pos.time);
+            pos.paused = true;
+        }
+    }
+
+    /// Advances the playlist to the given time.
+    /// Returns the scene to show, and the end of its step, if a new step
+    /// started.
+    pub fn tick(&mut self, time_ms: u128) -> Option<(Scene, Option<u128>)> {
+        let mut pos = self.state.filter(|pos| !pos.paused)?;
+        // Steps which ended before this tick are skipped, but a repeating
+        // playlist doesn't go around more than once.
+        for _ in 0..=self.playlist.steps.len() {
+            match self.step_end(&pos) {
+                Some(end) if end <= time_ms => {
+                    pos.step += 1;
+                    pos.time = end.max(pos.time);
+                    pos.cue = true;
+                    if pos.step == self.playlist.steps.len() {
+                        if !self.playlist.repeat {
+                            self.state = None;
+                            return None;
+                        }
+                        pos.step = 0;
+                    }
+                }
+                _ => break,
+            }
+        }
+        let cue = pos.cue;
+        pos.cue = false;
+        self.state = Some(pos);
+        cue.then(|| {
+            (
+                self.playlist.steps[pos.step].scene.clone(),
+                self.step_end(&pos),
+            )
+        })
+    }
+
+    pub fn status(&self, time_ms: u128) -> PlaylistStatus {
+        PlaylistStatus {
+            state: match self.state {
+                None => PlayerState::Stopped,
+                Some(pos) if pos.paused => PlayerState::Paused,
+                Some(_) => PlayerState::Running,
+            },
+            step: self.state.map(|pos| pos.step),
+            step_end_ms: self.state.and_then(|pos| match pos.paused {
+                // A paused step ends once it is resumed.
+                true => self.step_end(&Position {
+                    time: time_ms.saturating_sub(pos.time),
+                    ..pos
+                }),
+                false => self.step_end(&pos),
+            }),
+            playlist: self.playlist.clone(),
+        }
+    }
+
+    fn step_end(&self, pos: &Position) -> Option<u128> {
+        match self.playlist.steps[pos.step].until {
+            // The duration comes from the admin, and can be anything.
+            Until::Duration(ms) => Some(pos.time.saturating_add(ms)),
+            Until::Time(ms) => Some(ms),
+            Until::Forever => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn player(repeat: bool) -> Player {
+        let mut player = Player::default();
+        player.upload(Playlist {
+            steps: vec![
+                Step {
+                    scene: Scene::Icon(IconType::Fosdem),
+                    until: Until::Duration(100),
+                },
+                Step {
+                    scene: Scene::Countdown,
+                    until: Until::Time(1500),
+                },
+                Step {
+                    scene: Scene::Function,
+                    until: Until::Duration(1000),
+                },
+            ],
+            repeat,
+        });
+        player
+    }
+
+    #[test]
+    fn test_playlist() {
+        let mut player = player(false);
+        assert_eq!(player.tick(1000), None);
+
+        player.start(1000);
+        let icon = Some((Scene::Icon(IconType::Fosdem), Some(1100)));
+        assert_eq!(player.tick(1000), icon);
+        assert_eq!(player.tick(1050), None);
+        assert_eq!(player.tick(1100), Some((Scene::Countdown, Some(1500))));
+        assert_eq!(player.tick(1499), None);
+        // Steps follow the schedule, even if the ticks are late
+        assert_eq!(player.tick(1600), Some((Scene::Function, Some(2500))));
+        assert_eq!(player.status(1600).state, PlayerState::Running);
+
+        // The last step ends the playlist
+        assert_eq!(player.tick(2600), None);
+        assert_eq!(player.status(2600).state, PlayerState::Stopped);
+
+        // Starting again goes back to the first step
+        player.start(3000);
+        assert_eq!(player.tick(3000).unwrap().1, Some(3100));
+    }
+
+    #[test]
+    fn test_playlist_pause() {
+        let mut player = player(false);
+        player.start(1000);
+        player.tick(1000);
+
+        player.pause(1040);
+        assert_eq!(player.tick(2000), None);
+        let status = player.status(2000);
+        assert_eq!(status.state, PlayerState::Paused);
+        assert_eq!((status.step, status.step_end_ms), (Some(0), Some(2060)));
+
+        // Resuming shows the same step again, with the time left
+        player.start(2000);
+        let icon = Some((Scene::Icon(IconType::Fosdem), Some(2060)));
+        assert_eq!(player.tick(2000), icon);
+        // The wall-clock time of the countdown has already passed
+        assert_eq!(player.tick(2060), Some((Scene::Function, Some(3060))));
+    }
+
+    #[test]
+    fn test_playlist_repeat() {
+        let mut player = player(true);
+        player.start(1000);
+        player.tick(1000);
+        player.tick(1600);
+        let icon = Some((Scene::Icon(IconType::Fosdem), Some(2600)));
+        assert_eq!(player.tick(2550), icon);
+        // The countdown is skipped now, as 1500 is in the past
+        assert_eq!(player.tick(2600), Some((Scene::Function, Some(3600))));
+    }
+
+    #[test]
+    fn test_playlist_valid() {
+        assert!(player(false).playlist.is_valid());
+        assert!(!Playlist::default().is_valid());
+        let forever = |scene| Playlist {
+            steps: vec![Step {
+                scene,
+                until: Until::Forever,
+            }],
+            repeat: false,
+        };
+        assert!(forever(Scene::Function).is_valid());
+        assert!(!forever(Scene::Countdown).is_valid());
+        let mut wall_clock = Playlist {
+            steps: vec![Step {
+                scene: Scene::Countdown,
+                until: Until::Time(1000),
+            }],
+            repeat: false,
+        };
+        assert!(wall_clock.is_valid());
+        wall_clock.repeat = true;
+        assert!(!wall_clock.is_valid());
+    }
+
+    #[test]
+    fn test_playlist_long() {
+        let mut player = Player::default();
+        player.upload(Playlist {
+            steps: vec![Step {
+                scene: Scene::Function,
+                until: Until::Duration(u128::MAX),
+            }],
+            repeat: false,
+        });
+        player.start(1000);
+        assert_eq!(player.tick(2000), Some((Scene::Function, Some(u128::MAX))));
+        assert_eq!(player.tick(3000), None);
+        assert_eq!(player.status(3000).step_end_ms, Some(u128::MAX));
+    }
+}
@@ -0,0 +1,623 @@
+use std::{
+    f32::consts::PI,
+    fmt,
+    hash::{Hash, Hasher},
+    mem,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::hat::infix;
+
+/// Maximum number of values a compiled formula may hold on its stack.
+pub const STACK_SIZE: usize = 32;
+
+/// Maximum length of a formula, in bytes.
+pub const FORMULA_MAX: usize = 1000;
+
+/// The notation a formula is written in.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Syntax {
+    /// Reverse polish notation: `t x + sin`
+    #[default]
+    Rpn,
+    /// Infix notation: `sin(t + x)`
+    Infix,
+}
+
+/// One instruction of a compiled formula.
+/// Constants compare by their bits, so programs can be hashed.
+#[derive(Debug, Clone, Copy)]
+pub enum Op {
+    // Values
+    Const(f32),
+    X,
+    Y,
+    T,
+    // Stack manipulation
+    Dup,
+    Swap,
+    Drop,
+    Over,
+    Rot,
+    // Binary operators
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Pow,
+    Min,
+    Max,
+    Step,
+    Hypot,
+    Atan2,
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+    Equal,
+    And,
+    Or,
+    // Ternary functions
+    Select,
+    Clamp,
+    Mix,
+    Smoothstep,
+    // Unary functions
+    Neg,
+    Cos,
+    Sin,
+    Tan,
+    Acos,
+    Asin,
+    Atan,
+    Sqrt,
+    Exp,
+    Abs,
+    Floor,
+    Ceil,
+    Fract,
+    Sign,
+    Ln,
+    Not,
+}
+
+impl PartialEq for Op {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Op::Const(a), Op::Const(b)) => a.to_bits() == b.to_bits(),
+            _ => mem::discriminant(self) == mem::discriminant(other),
+        }
+    }
+}
+
+impl Eq for Op {}
+
+impl Hash for Op {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(self).hash(state);
+        if let Op::Const(v) = self {
+            v.to_bits().hash(state);
+        }
+    }
+}
+
+impl Op {
+    /// Returns the operation for this token, or None if the token is unknown.
+    pub fn parse(token: &str) -> Option<Op> {
+        Some(match token {
+            "x" => Op::X,
+            "y" => Op::Y,
+            "t" => Op::T,
+            "dup" => Op::Dup,
+            "swap" => Op::Swap,
+            "drop" => Op::Drop,
+            "over" => Op::Over,
+            "rot" => Op::Rot,
+            "+" => Op::Add,
+            "-" => Op::Sub,
+            "*" => Op::Mul,
+            "/" => Op::Div,
+            "%" => Op::Rem,
+            "^" | "pow" => Op::Pow,
+            "min" => Op::Min,
+            "max" => Op::Max,
+            "step" => Op::Step,
+            "hypot" => Op::Hypot,
+            "atan2" => Op::Atan2,
+            "<" => Op::Less,
+            ">" => Op::Greater,
+            "<=" => Op::LessEqual,
+            ">=" => Op::GreaterEqual,
+            "==" => Op::Equal,
+            "and" => Op::And,
+            "or" => Op::Or,
+            "?" => Op::Select,
+            "clamp" => Op::Clamp,
+            "mix" => Op::Mix,
+            "smoothstep" => Op::Smoothstep,
+            "neg" => Op::Neg,
+            "cos" => Op::Cos,
+            "sin" => Op::Sin,
+            "tan" => Op::Tan,
+            "acos" => Op::Acos,
+            "asin" => Op::Asin,
+            "atan" => Op::Atan,
+            "sqrt" => Op::Sqrt,
+            "exp" => Op::Exp,
+            "abs" => Op::Abs,
+            "floor" => Op::Floor,
+            "ceil" => Op::Ceil,
+            "fract" => Op::Fract,
+            "sign" => Op::Sign,
+            "ln" => Op::Ln,
+            "not" => Op::Not,
+            _ => return token.parse::<f32>().ok().map(Op::Const),
+        })
+    }
+
+    /// Number of values popped from and pushed to the stack.
+    pub fn arity(&self) -> (usize, usize) {
+        match self {
+            Op::Const(_) | Op::X | Op::Y | Op::T => (0, 1),
+            Op::Dup => (1, 2),
+            Op::Swap => (2, 2),
+            Op::Drop => (1, 0),
+            Op::Over => (2, 3),
+            Op::Rot => (3, 3),
+            Op::Add
+            | Op::Sub
+            | Op::Mul
+            | Op::Div
+            | Op::Rem
+            | Op::Pow
+            | Op::Min
+            | Op::Max
+            | Op::Step
+            | Op::Hypot
+            | Op::Atan2
+            | Op::Less
+            | Op::Greater
+            | Op::LessEqual
+            | Op::GreaterEqual
+            | Op::Equal
+            | Op::And
+            | Op::Or => (2, 1),
+            Op::Select | Op::Clamp | Op::Mix | Op::Smoothstep => (3, 1),
+            Op::Neg
+            | Op::Cos
+            | Op::Sin
+            | Op::Tan
+            | Op::Acos
+            | Op::Asin
+            | Op::Atan
+            | Op::Sqrt
+            | Op::Exp
+            | Op::Abs
+            | Op::Floor
+            | Op::Ceil
+            | Op::Fract
+            | Op::Sign
+            | Op::Ln
+            | Op::Not => (1, 1),
+        }
+    }
+
+    fn unary(&self, a: f32) -> f32 {
+        match self {
+            Op::Neg => -a,
+            Op::Cos => (a * PI).cos(),
+            Op::Sin => (a * PI).sin(),
+            Op::Tan => (a * PI).tan(),
+            Op::Acos => a.acos() / PI,
+            Op::Asin => a.asin() / PI,
+            Op::Atan => a.atan() / PI,
+            Op::Sqrt => a.sqrt(),
+            Op::Exp => a.exp(),
+            Op::Abs => a.abs(),
+            Op::Floor => a.floor(),
+            Op::Ceil => a.ceil(),
+            Op::Fract => a - a.floor(),
+            Op::Sign if a == 0. => 0.,
+            Op::Sign => a.signum(),
+            Op::Ln => a.ln(),
+            Op::Not => Self::truth(a == 0.),
+            _ => unreachable!("{self:?} is not unary"),
+        }
+    }
+
+    fn binary(&self, a: f32, b: f32) -> f32 {
+        match self {
+            Op::Add => a + b,
+            Op::Sub => a - b,
+            Op::Mul => a * b,
+            Op::Div if b != 0. => a / b,
+            Op::Rem if b != 0. => a % b,
+            Op::Div | Op::Rem => 0.,
+            Op::Pow => a.powf(b),
+            Op::Min => a.min(b),
+            Op::Max => a.max(b),
+            // Like GLSL: 0 if b is below the edge a, 1 otherwise
+            Op::Step if b < a => 0.,
+            Op::Step => 1.,
+            Op::Hypot => a.hypot(b),
+            // Like atan, the angle is divided by PI
+            Op::Atan2 => a.atan2(b) / PI,
+            Op::Less => Self::truth(a < b),
+            Op::Greater => Self::truth(a > b),
+            Op::LessEqual => Self::truth(a <= b),
+            Op::GreaterEqual => Self::truth(a >= b),
+            Op::Equal => Self::truth(a == b),
+            Op::And => Self::truth(a != 0. && b != 0.),
+            Op::Or => Self::truth(a != 0. || b != 0.),
+            _ => unreachable!("{self:?} is not binary"),
+        }
+    }
+
+    // Any value other than 0 is true, and true is 1.
+    fn truth(b: bool) -> f32 {
+        if b {
+            1.
+        } else {
+            0.
+        }
+    }
+
+    fn ternary(&self, a: f32, b: f32, c: f32) -> f32 {
+        match self {
+            // cond ? a : b
+            Op::Select if a != 0. => b,
+            Op::Select => c,
+            // clamp(x, min, max), ignoring an inverted range
+            Op::Clamp => a.max(b).min(c),
+            // mix(from, to, ratio)
+            Op::Mix => a + (b - a) * c,
+            // smoothstep(edge0, edge1, x)
+            Op::Smoothstep => {
+                let v = if a == b {
+                    Op::Step.binary(a, c)
+                } else {
+                    ((c - a) / (b - a)).clamp(0., 1.)
+                };
+                v * v * (3. - 2. * v)
+            }
+            _ => unreachable!("{self:?} is not ternary"),
+        }
+    }
+}
+
+/// Why a formula could not be compiled.
+/// Indexes count the whitespace separated tokens, starting at 0.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ProgramError {
+    UnknownToken {
+        index: usize,
+        token: String,
+    },
+    StackUnderflow {
+        index: usize,
+        token: String,
+    },
+    StackOverflow {
+        index: usize,
+        token: String,
+    },
+    NonFiniteConstant {
+        index: usize,
+        token: String,
+    },
+    LeftoverItems {
+        count: usize,
+    },
+    UnexpectedToken {
+        index: usize,
+        token: String,
+    },
+    UnexpectedEnd,
+    ArgumentCount {
+        index: usize,
+        token: String,
+        expected: usize,
+        found: usize,
+    },
+    TooLong {
+        length: usize,
+    },
+    TooDeep {
+        index: usize,
+        token: String,
+    },
+}
+
+impl fmt::Display for ProgramError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProgramError::UnknownToken { index, token } => {
+                write!(f, "unknown token '{token}' at index {index}")
+            }
+            ProgramError::StackUnderflow { index, token } => {
+                write!(f, "not enough operands for '{token}' at index {index}")
+            }
+            ProgramError::StackOverflow { index, token } => {
+                write!(
+                    f,
+                    "more than {STACK_SIZE} values on the stack at '{token}' at index {index}"
+                )
+            }
+            ProgramError::NonFiniteConstant { index, token } => {
+                write!(
+                    f,
+                    "constant '{token}' at index {index} is not a finite number"
+                )
+            }
+            ProgramError::LeftoverItems { count } => {
+                write!(f, "{count} values left on the stack instead of one")
+            }
+            ProgramError::UnexpectedToken { index, token } => {
+                write!(f, "unexpected '{token}' at index {index}")
+            }
+            ProgramError::UnexpectedEnd => write!(f, "unexpected end of formula"),
+            ProgramError::ArgumentCount {
+                index,
+                token,
+                expected,
+                found,
+            } => write!(
+                f,
+                "'{token}' at index {index} takes {expected} arguments, not {found}"
+            ),
+            ProgramError::TooLong { length } => {
+                write!(f, "formula of {length} bytes, more than {FORMULA_MAX}")
+            }
+            ProgramError::TooDeep { index, token } => {
+                write!(f, "formula nested too deeply at '{token}' at index {index}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProgramError {}
+
+/// A formula compiled into a sequence of instructions.
+/// Every instruction is guaranteed to find its operands on the stack,
+/// and the stack never grows beyond [STACK_SIZE].
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Program {
+    ops: Vec<Op>,
+    // Number of values left on the stack at the end.
+    depth: usize,
+    // Highest number of values on the stack during execution.
+    max_depth: usize,
+}
+
+impl Program {
+    /// Compiles a formula in the given syntax.
+    /// An empty formula is valid and evaluates to 0, every other formula
+    /// must leave exactly one value on the stack.
+    pub fn compile(raw: &str, syntax: Syntax) -> Result<Self, ProgramError> {
+        if raw.len() > FORMULA_MAX {
+            return Err(ProgramError::TooLong { length: raw.len() });
+        }
+        match syntax {
+            Syntax::Rpn => Self::compile_rpn(raw),
+            Syntax::Infix => infix::compile(raw),
+        }
+    }
+
+    fn compile_rpn(raw: &str) -> Result<Self, ProgramError> {
+        let mut prog = Program::default();
+        for (index, token) in raw.split_whitespace().enumerate() {
+            let op = Op::parse(token).ok_or_else(|| ProgramError::UnknownToken {
+                index,
+                token: token.into(),
+            })?;
+            prog.push(index, token, op)?;
+        }
+        prog.finish()
+    }
+
+    /// Appends an operation, checking that it finds its operands on the stack.
+    /// The index and token are only used for the error.
+    pub fn push(&mut self, index: usize, token: &str, op: Op) -> Result<(), ProgramError> {
+        if let Op::Const(v) = op {
+            if !v.is_finite() {
+                return Err(ProgramError::NonFiniteConstant {
+                    index,
+                    token: token.into(),
+                });
+            }
+        }
+        let (pops, pushes) = op.arity();
+        if self.depth < pops {
+            return Err(ProgramError::StackUnderflow {
+                index,
+                token: token.into(),
+            });
+        }
+        self.depth = self.depth - pops + pushes;
+        if self.depth > STACK_SIZE {
+            return Err(ProgramError::StackOverflow {
+                index,
+                token: token.into(),
+            });
+        }
+        self.max_depth = self.max_depth.max(self.depth);
+        self.ops.push(op);
+        Ok(())
+    }
+
+    /// Checks that the program leaves at most one value on the stack.
+    pub fn finish(self) -> Result<Self, ProgramError> {
+        if self.depth > 1 {
+            return Err(ProgramError::LeftoverItems { count: self.depth });
+        }
+        Ok(self)
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Runs the program and returns the top of the stack, or 0 if empty.
+    pub fn run(&self, x: f32, y: f32, t: f32) -> f32 {
+        let mut stack = [0f32; STACK_SIZE];
+        let mut sp = 0;
+        for op in &self.ops {
+            match op {
+                Op::Const(v) => {
+                    stack[sp] = *v;
+                    sp += 1;
+                }
+                Op::X | Op::Y | Op::T => {
+                    stack[sp] = match op {
+                        Op::X => x,
+                        Op::Y => y,
+                        _ => t,
+                    };
+                    sp += 1;
+                }
+                // a -- a a
+                Op::Dup => {
+                    stack[sp] = stack[sp - 1];
+                    sp += 1;
+                }
+                // a b -- b a
+                Op::Swap => stack.swap(sp - 2, sp - 1),
+                // a --
+                Op::Drop => sp -= 1,
+                // a b -- a b a
+                Op::Over => {
+                    stack[sp] = stack[sp - 2];
+                    sp += 1;
+                }
+                // a b c -- b c a
+                Op::Rot => stack[sp - 3..sp].rotate_left(1),
+                _ => match op.arity().0 {
+                    1 => stack[sp - 1] = op.unary(stack[sp - 1]),
+                    2 => {
+                        sp -= 1;
+                        stack[sp - 1] = op.binary(stack[sp - 1], stack[sp]);
+                    }
+                    _ => {
+                        sp -= 2;
+                        stack[sp - 1] = op.ternary(stack[sp - 1], stack[sp], stack[sp + 1]);
+                    }
+                },
+            }
+        }
+        if sp > 0 {
+            stack[sp - 1]
+        } else {
+            0.
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(raw: &str, x: f32, y: f32, t: f32) -> f32 {
+        Program::compile(raw, Syntax::Rpn).unwrap().run(x, y, t)
+    }
+
+    #[test]
+    fn test_compile() {
+        let prog = Program::compile("t x + sin", Syntax::Rpn).unwrap();
+        assert_eq!(prog.ops, [Op::T, Op::X, Op::Add, Op::Sin]);
+        assert_eq!(prog.depth, 1);
+        assert_eq!(prog.max_depth(), 2);
+
+        assert_eq!(Program::compile("", Syntax::Rpn).unwrap().depth, 0);
+
+        // Constants compare by their bits
+        let rpn = |raw| Program::compile(raw, Syntax::Rpn).unwrap();
+        assert_eq!(rpn("x 1 +"), rpn("x  1.0 +"));
+        assert_ne!(rpn("x 1 +"), rpn("x 2 +"));
+        assert_ne!(rpn("x 0 +"), rpn("x -0 +"));
+        assert_ne!(rpn("x 1 +"), rpn("x 1 -"));
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        assert_eq!(
+            Program::compile("x foo +", Syntax::Rpn),
+            Err(ProgramError::UnknownToken {
+                index: 1,
+                token: "foo".into()
+            })
+        );
+        assert_eq!(
+            Program::compile("x sin 2 * +", Syntax::Rpn),
+            Err(ProgramError::StackUnderflow {
+                index: 4,
+                token: "+".into()
+            })
+        );
+        assert_eq!(
+            Program::compile(&"1 ".repeat(STACK_SIZE + 1), Syntax::Rpn),
+            Err(ProgramError::StackOverflow {
+                index: STACK_SIZE,
+                token: "1".into()
+            })
+        );
+        assert_eq!(
+            Program::compile("x inf *", Syntax::Rpn),
+            Err(ProgramError::NonFiniteConstant {
+                index: 1,
+                token: "inf".into()
+            })
+        );
+        assert_eq!(
+            Program::compile("x y", Syntax::Rpn),
+            Err(ProgramError::LeftoverItems { count: 2 })
+        );
+        assert_eq!(
+            Program::compile(&"x ".repeat(FORMULA_MAX), Syntax::Rpn),
+            Err(ProgramError::TooLong {
+                length: 2 * FORMULA_MAX
+            })
+        );
+    }
+
+    #[test]
+    fn test_run() {
+        assert_eq!(run("", 1., 2., 3.), 0.);
+        assert_eq!(run("x y - t *", 1., 2., 3.), -3.);
+        assert_eq!(run("x 0 /", 1., 2., 3.), 0.);
+        assert_eq!(run("y t pow", 1., 2., 3.), 8.);
+        assert!((run("0.5 sin", 0., 0., 0.) - 1.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_stack_words() {
+        assert_eq!(run("x dup *", 3., 0., 0.), 9.);
+        assert_eq!(run("x y swap -", 1., 2., 0.), 1.);
+        assert_eq!(run("x y drop", 1., 2., 0.), 1.);
+        assert_eq!(run("x y over - *", 1., 2., 0.), 1.);
+        assert_eq!(run("x y t rot - -", 1., 2., 3.), 0.);
+        assert_eq!(run("x y t rot drop -", 1., 2., 3.), -1.);
+
+        let prog = Program::compile("x dup dup * over", Syntax::Rpn);
+        assert_eq!(prog, Err(ProgramError::LeftoverItems { count: 3 }));
+        let prog = Program::compile("x y rot", Syntax::Rpn);
+        assert_eq!(
+            prog,
+            Err(ProgramError::StackUnderflow {
+                index: 2,
+                token: "rot".into()
+            })
+        );
+        let prog = Program::compile(&format!("x{}", " dup".repeat(STACK_SIZE)), Syntax::Rpn);
+        assert_eq!(
+            prog,
+            Err(ProgramError::StackOverflow {
+                index: STACK_SIZE,
+                token: "dup".into()
+            })
+        );
+    }
+}
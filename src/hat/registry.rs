@@ -0,0 +1,493 @@
+use std::{fs, path::PathBuf};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+
+use crate::hat::{
+    icon::IconType,
+    leds::LED,
+    motion::Animation,
+    sprite::{Frame, Playback, Sprite, FRAMES_MAX},
+};
+
+/// Maximum number of icons uploaded by the admin.
+pub const ICONS_MAX: usize = 100;
+/// Maximum length of the name of an icon.
+pub const NAME_MAX: usize = 32;
+/// Maximum number of colours, as the pattern uses one digit per colour.
+pub const PALETTE_MAX: usize = 10;
+
+/// An icon, built-in or uploaded by the admin: every line of the pattern
+/// of a frame is a row of the icon, every digit the index of its colour in
+/// the palette, and a '.' is left black.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(from = "StoredIcon")]
+pub struct IconDef {
+    pub name: String,
+    #[serde(flatten)]
+    pub sprite: Sprite,
+    pub palette: Vec<LED>,
+    #[serde(default)]
+    pub animation: Animation,
+}
+
+// An icon as written to the icons file, which has a single pattern
+// instead of frames if it was written before icons could be animated.
+#[derive(Deserialize)]
+struct StoredIcon {
+    name: String,
+    #[serde(flatten)]
+    sprite: StoredSprite,
+    palette: Vec<LED>,
+    #[serde(default)]
+    animation: Animation,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredSprite {
+    Frames(Sprite),
+    Still { pattern: String },
+}
+
+impl From<StoredIcon> for IconDef {
+    fn from(icon: StoredIcon) -> Self {
+        Self {
+            name: icon.name,
+            sprite: match icon.sprite {
+                StoredSprite::Frames(sprite) => sprite,
+                StoredSprite::Still { pattern } => Sprite::still(&pattern),
+            },
+            palette: icon.palette,
+            animation: icon.animation,
+        }
+    }
+}
+
+/// An icon as sent by the admin, either as a pattern or frames with
+/// their palette, or as a base64 encoded PNG or animated GIF.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct IconUpload {
+    pub name: String,
+    #[serde(default)]
+    pub pattern: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub frames: Vec<Frame>,
+    #[serde(default)]
+    pub playback: Playback,
+    #[serde(default)]
+    pub palette: Vec<LED>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub png: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gif: Option<String>,
+    #[serde(default)]
+    pub animation: Animation,
+}
+
+impl IconUpload {
+    /// Returns the icon if it has a valid name and all its frames fit in
+    /// the given `(width, height)` of the hat.
+    pub fn to_icon(&self, range: (usize, usize)) -> Option<IconDef> {
+        let valid_name = !self.name.is_empty()
+            && self.name.len() <= NAME_MAX
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            && matches!(IconType::from(self.name.clone()), IconType::Custom(_));
+        if !valid_name || !self.animation.is_valid() {
+            return None;
+        }
+        let (frames, palette) = match (&self.png, &self.gif) {
+            (Some(png), _) => {
+                let (pattern, palette) = from_png(&STANDARD.decode(png).ok()?, range)?;
+                (Sprite::still(&pattern).frames, palette)
+            }
+            (_, Some(gif)) => from_gif(&STANDARD.decode(gif).ok()?, range)?,
+            _ if self.frames.is_empty() => {
+                (Sprite::still(&self.pattern).frames, self.palette.clone())
+            }
+            _ => (self.frames.clone(), self.palette.clone()),
+        };
+        let mut sprite = Sprite {
+            frames,
+            playback: self.playback,
+        };
+        for frame in &mut sprite.frames {
+            frame.pattern = valid_pattern(&frame.pattern, range, palette.len())?;
+        }
+        (sprite.is_valid() && palette.len() <= PALETTE_MAX).then(|| IconDef {
+            name: self.name.clone(),
+            sprite,
+            palette,
+            animation: self.animation,
+        })
+    }
+}
+
+// Returns the lines of the pattern without spaces, if it fits on the hat
+// and only uses the colours of the palette.
+fn valid_pattern(pattern: &str, range: (usize, usize), colors: usize) -> Option<String> {
+    let lines = pattern
+        .split('\n')
+        .map(|l| l.replace(' ', ""))
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<_>>();
+    let valid = !lines.is_empty()
+        && lines.len() <= range.1
+        && lines.iter().all(|l| l.len() <= range.0)
+        && lines
+            .iter()
+            .flat_map(|l| l.chars())
+            .all(|c| c == '.' || c.to_digit(10).is_some_and(|i| (i as usize) < colors));
+    valid.then(|| lines.join("\n"))
+}
+
+// Converts a PNG to a pattern, with the colours in the order they appear.
+// Transparent pixels are left black, and images with more colours than
+// digits are refused, like images bigger than the hat, before decoding
+// them.
+fn from_png(data: &[u8], range: (usize, usize)) -> Option<(String, Vec<LED>)> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().ok()?;
+    let (width, height) = (reader.info().width, reader.info().height);
+    if width as usize > range.0 || height as usize > range.1 {
+        return None;
+    }
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).ok()?;
+    let channels = info.color_type.samples();
+    let mut pixels = vec![];
+    for line in buf.chunks(info.line_size).take(info.height as usize) {
+        for px in line.chunks(channels).take(info.width as usize) {
+            pixels.push(match info.color_type {
+                png::ColorType::Grayscale => [px[0], px[0], px[0], 0xff],
+                png::ColorType::GrayscaleAlpha => [px[0], px[0], px[0], px[1]],
+                png::ColorType::Rgb => [px[0], px[1], px[2], 0xff],
+                png::ColorType::Rgba => [px[0], px[1], px[2], px[3]],
+                png::ColorType::Indexed => return None,
+            });
+        }
+    }
+    let mut palette = vec![];
+    let pattern = to_pattern(&pixels, info.width as usize, &mut palette)?;
+    Some((pattern, palette))
+}
+
+// Converts an animated GIF to frames sharing one palette.
+// Every frame of the GIF is drawn over the previous ones, as GIFs often
+// only store what changed.
+// GIFs bigger than the hat are refused before decoding any frame.
+fn from_gif(data: &[u8], range: (usize, usize)) -> Option<(Vec<Frame>, Vec<LED>)> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::RGBA);
+    let mut decoder = options.read_info(data).ok()?;
+    let width = decoder.width() as usize;
+    if width > range.0 || decoder.height() as usize > range.1 {
+        return None;
+    }
+    let mut canvas = vec![[0u8; 4]; width * decoder.height() as usize];
+    let mut palette = vec![];
+    let mut frames = vec![];
+    while let Some(frame) = decoder.read_next_frame().ok()? {
+        if frames.len() == FRAMES_MAX {
+            return None;
+        }
+        let previous = canvas.clone();
+        let (left, top) = (frame.left as usize, frame.top as usize);
+        let mut rect = vec![];
+        for (i, px) in frame.buffer.chunks(4).enumerate() {
+            let (x, y) = (
+                left + i % frame.width as usize,
+                top + i / frame.width as usize,
+            );
+            if x < width && y * width + x < canvas.len() {
+                rect.push(y * width + x);
+                if px[3] >= 0x80 {
+                    canvas[y * width + x] = [px[0], px[1], px[2], px[3]];
+                }
+            }
+        }
+        frames.push(Frame {
+            pattern: to_pattern(&canvas, width, &mut palette)?,
+            // The delay is in 1/100 s, and most browsers show frames
+            // without a delay for 100 ms.
+            duration_ms: match frame.delay {
+                0 => 100,
+                delay => delay as u64 * 10,
+            },
+        });
+        match frame.dispose {
+            gif::DisposalMethod::Background => rect.iter().for_each(|&i| canvas[i] = [0; 4]),
+            gif::DisposalMethod::Previous => canvas = previous,
+            _ => {}
+        }
+    }
+    Some((frames, palette))
+}
+
+// Converts RGBA pixels to a pattern, adding new colours to the palette.
+// Transparent pixels are left black, and patterns with more colours than
+// digits are refused.
+fn to_pattern(pixels: &[[u8; 4]], width: usize, palette: &mut Vec<LED>) -> Option<String> {
+    let mut pattern = String::new();
+    for line in pixels.chunks(width.max(1)) {
+        for px in line {
+            if px[3] < 0x80 {
+                pattern.push('.');
+                continue;
+            }
+            let led = LED::from_rgb(px[0], px[1], px[2]);
+            let index = match palette.iter().position(|c| c == &led) {
+                Some(index) => index,
+                None => {
+                    palette.push(led);
+                    palette.len() - 1
+                }
+            };
+            pattern.push(char::from_digit(index as u32, 10)?);
+        }
+        pattern.push('\n');
+    }
+    Some(pattern)
+}
+
+/// Keeps the icons uploaded by the admin, and writes them to a JSON file,
+/// if one is given.
+#[derive(Default)]
+pub struct IconRegistry {
+    path: Option<PathBuf>,
+    icons: Vec<IconDef>,
+}
+
+impl IconRegistry {
+    /// Reads the icons from the given file, or starts without icons if the
+    /// file doesn't exist or cannot be parsed.
+    pub fn open(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let icons = match fs::read_to_string(&path) {
+            Ok(s) => serde_json::from_str(&s).unwrap_or_else(|e| {
+                tracing::warn!("Ignoring invalid icons file {path:?}: {e}");
+                vec![]
+            }),
+            Err(_) => vec![],
+        };
+        tracing::info!("Loaded {} icons", icons.len());
+        IconRegistry {
+            path: Some(path),
+            icons,
+        }
+    }
+
+    /// Adds the icon, or replaces the one with the same name.
+    /// Returns false if there are already [ICONS_MAX] other icons.
+    pub fn insert(&mut self, icon: IconDef) -> bool {
+        match self.icons.iter().position(|i| i.name == icon.name) {
+            Some(index) => self.icons[index] = icon,
+            None if self.icons.len() < ICONS_MAX => self.icons.push(icon),
+            None => return false,
+        }
+        self.save();
+        true
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.icons.len();
+        self.icons.retain(|i| i.name != name);
+        let removed = self.icons.len() < len;
+        if removed {
+            self.save();
+        }
+        removed
+    }
+
+    pub fn get(&self, name: &str) -> Option<&IconDef> {
+        self.icons.iter().find(|i| i.name == name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.icons.iter().map(|i| i.name.as_str())
+    }
+
+    // The icons are few and small, so the whole file is rewritten, through
+    // a temporary file to never leave half of it behind.
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let tmp = path.with_extension("tmp");
+        let written = serde_json::to_string(&self.icons)
+            .map_err(|e| e.to_string())
+            .and_then(|s| fs::write(&tmp, s).map_err(|e| e.to_string()))
+            .and_then(|_| fs::rename(&tmp, path).map_err(|e| e.to_string()));
+        if let Err(e) = written {
+            tracing::error!("Couldn't write icons to {path:?}: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn upload(name: &str, pattern: &str) -> IconUpload {
+        IconUpload {
+            name: name.into(),
+            pattern: pattern.into(),
+            palette: vec![LED::black(), LED::from_hex("204040")],
+            ..IconUpload::default()
+        }
+    }
+
+    #[test]
+    fn test_upload() {
+        let icon = upload("Logo", "\n  0110\n  1..1\n")
+            .to_icon((73, 9))
+            .unwrap();
+        assert_eq!(icon.sprite, Sprite::still("0110\n1..1"));
+
+        // Colours outside of the palette, too big, or bad names
+        assert!(upload("Logo", "012").to_icon((73, 9)).is_none());
+        assert!(upload("Logo", "0110").to_icon((3, 9)).is_none());
+        assert!(upload("Logo", "0\n1\n0").to_icon((73, 2)).is_none());
+        assert!(upload("Fish", "0110").to_icon((73, 9)).is_none());
+        assert!(upload("a b", "0110").to_icon((73, 9)).is_none());
+        assert!(upload("", "0110").to_icon((73, 9)).is_none());
+
+        // Every frame must fit
+        let frames = |second: &str| IconUpload {
+            frames: vec![Frame::new("01", 100), Frame::new(second, 50)],
+            playback: Playback::PingPong,
+            ..upload("Blink", "")
+        };
+        let icon = frames("10").to_icon((73, 9)).unwrap();
+        assert_eq!(icon.sprite.pattern(120), "10");
+        assert!(frames("12").to_icon((73, 9)).is_none());
+    }
+
+    #[test]
+    fn test_png() {
+        // A 2x2 RGBA image with a transparent pixel
+        let mut data = vec![];
+        let mut encoder = png::Encoder::new(&mut data, 2, 2);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let pixels = [
+            [0xff, 0, 0, 0xff],
+            [0, 0, 0, 0],
+            [0, 0xff, 0, 0xff],
+            [0xff, 0, 0, 0xff],
+        ];
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(pixels.as_flattened()).unwrap();
+        writer.finish().unwrap();
+
+        let icon = IconUpload {
+            name: "Dots".into(),
+            png: Some(STANDARD.encode(&data)),
+            ..IconUpload::default()
+        }
+        .to_icon((73, 9))
+        .unwrap();
+        assert_eq!(icon.sprite, Sprite::still("0.\n10"));
+        assert_eq!(
+            icon.palette,
+            vec![LED::from_rgb(0xff, 0, 0), LED::from_rgb(0, 0xff, 0)]
+        );
+
+        // Too big for the hat
+        let upload = IconUpload {
+            name: "Dots".into(),
+            png: Some(STANDARD.encode(&data)),
+            ..IconUpload::default()
+        };
+        assert!(upload.to_icon((1, 9)).is_none());
+    }
+
+    #[test]
+    fn test_gif() {
+        // Two frames of 2x1 pixels, the second one only changing the
+        // right pixel, and then going back to the first frame.
+        let mut data = vec![];
+        let palette = [0, 0, 0, 0xff, 0, 0];
+        let mut encoder = gif::Encoder::new(&mut data, 2, 1, &palette).unwrap();
+        let mut first = gif::Frame::from_indexed_pixels(2, 1, vec![1, 0], None);
+        first.delay = 20;
+        encoder.write_frame(&first).unwrap();
+        let mut second = gif::Frame::from_indexed_pixels(1, 1, vec![1], None);
+        second.left = 1;
+        second.dispose = gif::DisposalMethod::Previous;
+        encoder.write_frame(&second).unwrap();
+        encoder.write_frame(&first).unwrap();
+        drop(encoder);
+
+        let icon = IconUpload {
+            name: "Blink".into(),
+            gif: Some(STANDARD.encode(&data)),
+            ..IconUpload::default()
+        }
+        .to_icon((73, 9))
+        .unwrap();
+        let frames = icon.sprite.frames;
+        assert_eq!(frames[0], Frame::new("01", 200));
+        assert_eq!(frames[1], Frame::new("00", 100));
+        assert_eq!(frames[2], Frame::new("01", 200));
+        assert_eq!(
+            icon.palette,
+            vec![LED::from_rgb(0xff, 0, 0), LED::from_rgb(0, 0, 0)]
+        );
+
+        // A screen bigger than the hat is refused without allocating it
+        let mut data = vec![];
+        let mut encoder = gif::Encoder::new(&mut data, u16::MAX, u16::MAX, &palette).unwrap();
+        encoder.write_frame(&first).unwrap();
+        drop(encoder);
+        let upload = IconUpload {
+            name: "Huge".into(),
+            gif: Some(STANDARD.encode(&data)),
+            ..IconUpload::default()
+        };
+        assert!(upload.to_icon((73, 9)).is_none());
+    }
+
+    #[test]
+    fn test_registry() {
+        let path = std::env::temp_dir().join(format!("icons-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut registry = IconRegistry::open(&path);
+        let icon = upload("Logo", "0110").to_icon((73, 9)).unwrap();
+        assert!(registry.insert(icon.clone()));
+        assert!(registry.insert(icon.clone()));
+        assert_eq!(registry.names().collect::<Vec<_>>(), vec!["Logo"]);
+
+        // The icons survive a restart
+        let mut registry = IconRegistry::open(&path);
+        assert_eq!(registry.get("Logo"), Some(&icon));
+        assert!(registry.remove("Logo"));
+        assert!(!registry.remove("Logo"));
+        assert!(IconRegistry::open(&path).get("Logo").is_none());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_still_icons_file() {
+        // Written before icons had frames
+        let path = std::env::temp_dir().join(format!("icons-old-{}.json", std::process::id()));
+        fs::write(
+            &path,
+            r#"[{"name":"Logo","pattern":"0110\n1..1","palette":["000000","204040"],
+                "animation":{"motion":{"kind":"scroll","speed":2.0},"cycle_ms":null}},
+               {"name":"Dot","pattern":"1","palette":["000000","ff0000"]}]"#,
+        )
+        .unwrap();
+        let registry = IconRegistry::open(&path);
+        assert_eq!(registry.names().collect::<Vec<_>>(), vec!["Logo", "Dot"]);
+        let icon = registry.get("Logo").unwrap();
+        assert_eq!(icon.sprite, Sprite::still("0110\n1..1"));
+        assert_eq!(icon.palette[1], LED::from_hex("204040"));
+        assert_eq!(registry.get("Dot").unwrap().animation, Animation::default());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,121 @@
+use serde::{Deserialize, Serialize};
+
+/// Maximum number of frames of a sprite.
+pub const FRAMES_MAX: usize = 64;
+
+/// One picture of a sprite, in the pattern format of the icons.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Frame {
+    pub pattern: String,
+    // How long the frame is shown.
+    #[serde(default = "Frame::default_duration")]
+    pub duration_ms: u64,
+}
+
+impl Frame {
+    fn default_duration() -> u64 {
+        100
+    }
+
+    pub fn new(pattern: &str, duration_ms: u64) -> Self {
+        Self {
+            pattern: pattern.into(),
+            duration_ms,
+        }
+    }
+}
+
+/// In which order the frames are shown.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Playback {
+    // Starts over with the first frame after the last one.
+    #[default]
+    Loop,
+    // Goes back from the last frame to the first one.
+    PingPong,
+}
+
+/// The frames of an icon, of which only one is shown at a time.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Sprite {
+    pub frames: Vec<Frame>,
+    #[serde(default)]
+    pub playback: Playback,
+}
+
+impl Sprite {
+    /// A sprite which doesn't change.
+    pub fn still(pattern: &str) -> Self {
+        Self {
+            frames: vec![Frame::new(pattern, Frame::default_duration())],
+            playback: Playback::Loop,
+        }
+    }
+
+    /// Checks the number of frames and their durations, but not their
+    /// patterns, which depend on the hat.
+    pub fn is_valid(&self) -> bool {
+        !self.frames.is_empty()
+            && self.frames.len() <= FRAMES_MAX
+            && self.frames.iter().all(|f| f.duration_ms > 0)
+    }
+
+    /// The pattern of the frame shown at the given time.
+    pub fn pattern(&self, time_ms: u128) -> &str {
+        let order = self.order();
+        let total: u128 = order
+            .iter()
+            .map(|&i| self.frames[i].duration_ms as u128)
+            .sum();
+        let mut time = time_ms % total.max(1);
+        for i in order {
+            let duration = self.frames[i].duration_ms as u128;
+            if time < duration {
+                return &self.frames[i].pattern;
+            }
+            time -= duration;
+        }
+        &self.frames[0].pattern
+    }
+
+    // The indexes of the frames for one period of the playback.
+    fn order(&self) -> Vec<usize> {
+        let len = self.frames.len();
+        match self.playback {
+            Playback::Loop => (0..len).collect(),
+            Playback::PingPong => (0..len).chain((1..len.saturating_sub(1)).rev()).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sprite() {
+        let mut sprite = Sprite {
+            frames: vec![
+                Frame::new("0", 100),
+                Frame::new("1", 50),
+                Frame::new("2", 100),
+            ],
+            playback: Playback::Loop,
+        };
+        let patterns = |sprite: &Sprite| {
+            (0..6)
+                .map(|i| sprite.pattern(i * 50).to_string())
+                .collect::<String>()
+        };
+        assert_eq!(patterns(&sprite), "001220");
+        sprite.playback = Playback::PingPong;
+        assert_eq!(patterns(&sprite), "001221");
+        assert_eq!(sprite.pattern(300), "0");
+
+        assert!(sprite.is_valid());
+        assert!(Sprite::still("1").is_valid());
+        sprite.frames[0].duration_ms = 0;
+        assert!(!sprite.is_valid());
+    }
+}
@@ -1,108 +1,352 @@
+use std::net::SocketAddr;
+
 use serde::{Deserialize, Serialize};
 
 use crate::{
     hat::{
-        countdown::Countdown,
-        function::{FormulaStrings, Function},
+        countdown::{Countdown, CountdownPhases, CountdownState, Phase, TimerMode, TimerStatus},
+        function::{FormulaStrings, Function, QueueListing, SubmitError},
+        geometry::Geometry,
+        history::History,
         icon::{Icon, IconType},
-        leds::LED,
+        leds::{OutputMapping, LED},
+        playlist::{Player, Playlist, PlaylistStatus, Scene},
+        registry::{IconRegistry, IconUpload},
+        telemetry::{DeviceTelemetry, Telemetry},
+        text::{Text, TextConfig},
+        transition::{Transition, TransitionConfig},
     },
-    AdminCommand,
+    HatCommand,
 };
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct HatStatus {
-    command: AdminCommand,
+    command: HatCommand,
     formulas_queue: usize,
     allow_function: bool,
+    output_mapping: OutputMapping,
+    transition: TransitionConfig,
+    // The phase of the countdown shown, if any.
+    countdown_phase: Option<Phase>,
+    // The countdown or stopwatch, while it is shown.
+    timer: Option<TimerStatus>,
+    // The device whose LEDs are shown instead, if any.
+    mirror: Option<String>,
+    telemetry: DeviceTelemetry,
+}
+
+/// What a hat shows, to show the same on another hat.
+#[derive(Debug, Clone)]
+pub struct HatScene {
+    state: HatState,
+    allow_function: bool,
+    icon: IconType,
+    countdown: CountdownState,
+    text: TextConfig,
+    text_start: u128,
+    transition: TransitionConfig,
+    output_mapping: OutputMapping,
+}
+
+impl HatScene {
+    pub fn icon(&self) -> &IconType {
+        &self.icon
+    }
 }
 
 pub struct Switch {
+    geometry: Geometry,
     function: Function,
+    history: History,
     icons: Icon,
     countdown: Countdown,
+    text: Text,
     state: HatState,
     allow_function: bool,
+    transition: Transition,
+    playlist: Player,
+    // The LEDs returned last, where the next transition starts from.
+    last_leds: Vec<LED>,
+    // The device whose LEDs are shown instead of the own ones.
+    mirror: Option<String>,
+    // What the hat sent with its last requests.
+    telemetry: DeviceTelemetry,
 }
 
-#[derive(PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum HatState {
     Function,
     Icon,
     Countdown,
+    Text,
 }
 
 impl Switch {
-    pub fn new(leds: usize, circum: usize) -> Self {
+    pub fn new(
+        geometry: Geometry,
+        history: History,
+        registry: IconRegistry,
+        queue_limit: usize,
+    ) -> Self {
         Switch {
-            icons: Icon::new(leds, circum),
-            function: Function::new(leds, circum, 1000, 10000),
-            countdown: Countdown::new(leds, circum),
+            geometry,
+            history,
+            icons: Icon::new(geometry, registry),
+            function: Function::new(geometry, 1000, 10000, queue_limit),
+            countdown: Countdown::new(geometry),
+            text: Text::new(geometry),
             state: HatState::Function,
             allow_function: true,
+            transition: Transition::new(geometry),
+            playlist: Player::default(),
+            last_leds: vec![],
+            mirror: None,
+            telemetry: DeviceTelemetry::default(),
         }
     }
 
-    pub fn get_leds_string(&mut self) -> String {
-        self.get_leds()
-            .iter()
-            .map(|led| led.to_string())
-            .collect::<Vec<_>>()
-            .join("")
-    }
-
-    pub fn set_co2(&mut self, co2: u16) {
-        self.icons.set_co2(co2);
+    pub fn geometry(&self) -> Geometry {
+        self.geometry
     }
 
-    pub fn get_leds_binary(&mut self) -> Vec<u8> {
-        self.get_leds()
-            .iter()
-            .flat_map(|led| vec![led.red(), led.green(), led.blue()])
-            .collect()
+    /// Records the values sent by the hat with a request from the given
+    /// address.
+    /// The CO2 icon only shows values newer than the ones it has.
+    pub fn report(&mut self, telemetry: Telemetry, sequence: Option<u32>, source: SocketAddr) {
+        let co2 = telemetry.co2;
+        if self
+            .telemetry
+            .update(telemetry, sequence, source, Self::get_time())
+        {
+            if let Some(co2) = co2 {
+                self.icons.set_co2(co2);
+            }
+        }
     }
 
     pub fn get_status(&self) -> HatStatus {
+        let time = Self::get_time();
         HatStatus {
-            command: match self.state {
-                HatState::Function => AdminCommand::AllowFunction,
-                HatState::Icon => AdminCommand::Icon(self.icons.get_icon()),
-                HatState::Countdown => {
-                    AdminCommand::Countdown(self.countdown.get_minutes(Self::get_time()))
+            command: match (&self.state, self.countdown.mode()) {
+                (HatState::Function, _) => HatCommand::AllowFunction,
+                (HatState::Icon, _) => HatCommand::Icon(self.icons.get_icon()),
+                (HatState::Countdown, TimerMode::Countdown) => {
+                    HatCommand::Countdown(self.countdown.get_minutes(time))
                 }
+                (HatState::Countdown, TimerMode::Stopwatch) => HatCommand::Stopwatch,
+                (HatState::Text, _) => HatCommand::Text(self.text.get_text()),
             },
             formulas_queue: self.function.queue_len(),
             allow_function: self.allow_function,
+            output_mapping: self.function.output_mapping(),
+            transition: self.transition.config(),
+            countdown_phase: match self.state {
+                HatState::Countdown => self.countdown.get_phase(time),
+                _ => None,
+            },
+            timer: match self.state {
+                HatState::Countdown => Some(self.countdown.get_status(time)),
+                _ => None,
+            },
+            mirror: self.mirror.clone(),
+            telemetry: self.telemetry.clone(),
+        }
+    }
+
+    pub fn mirror(&self) -> Option<&str> {
+        self.mirror.as_deref()
+    }
+
+    pub fn set_mirror(&mut self, device: Option<String>) {
+        self.mirror = device;
+    }
+
+    pub fn scene(&self) -> HatScene {
+        HatScene {
+            state: self.state,
+            allow_function: self.allow_function,
+            icon: self.icons.get_icon(),
+            countdown: self.countdown.state(),
+            text: self.text.get_text(),
+            text_start: self.text.time_start(),
+            transition: self.transition.config(),
+            output_mapping: self.function.output_mapping(),
         }
     }
 
-    pub fn add_formula(&mut self, fs: FormulaStrings) {
-        self.function.add_formula(fs);
+    /// Shows the scene of another hat, with the same timer and text
+    /// position.
+    /// The formulas queue and the playlist stay with each hat.
+    pub fn sync(&mut self, scene: HatScene) {
+        self.pause_playlist();
+        self.icons.set_icon(scene.icon);
+        self.countdown.set_state(scene.countdown);
+        self.text.set_text(scene.text, scene.text_start);
+        self.transition.set_config(scene.transition);
+        self.function.set_output_mapping(scene.output_mapping);
+        self.set_state(scene.state);
+        self.allow_function = scene.allow_function;
+    }
+
+    pub fn add_formula(
+        &mut self,
+        fs: FormulaStrings,
+        submitter: String,
+    ) -> Result<(), SubmitError> {
+        let time = Self::get_time();
+        let compiled = self.function.add_formula(fs.clone(), submitter, time)?;
+        self.history.add(&fs, compiled, time);
         if self.allow_function && self.state != HatState::Function {
             self.set_state(HatState::Function);
         }
+        Ok(())
+    }
+
+    pub fn list_queue(&self) -> QueueListing {
+        self.function.list_queue()
+    }
+
+    pub fn remove_formula(&mut self, id: u64) -> bool {
+        self.function.remove_formula(id)
+    }
+
+    pub fn move_formula_to_front(&mut self, id: u64) -> bool {
+        self.function.move_to_front(id)
+    }
+
+    pub fn skip_formula(&mut self) -> bool {
+        self.function.skip_current()
+    }
+
+    pub fn clear_queue(&mut self) {
+        self.function.clear_queue();
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
     }
 
     pub fn set_state(&mut self, state: HatState) {
         self.allow_function = state == HatState::Function;
         self.state = state;
+        self.transition
+            .start(self.last_leds.clone(), Self::get_time());
     }
 
     pub fn allow_function(&mut self) {
         self.allow_function = true;
     }
 
+    pub fn set_output_mapping(&mut self, mapping: OutputMapping) {
+        self.function.set_output_mapping(mapping);
+    }
+
+    pub fn set_transition(&mut self, config: TransitionConfig) {
+        self.transition.set_config(config);
+    }
+
+    pub fn upload_playlist(&mut self, playlist: Playlist) {
+        self.playlist.upload(playlist);
+    }
+
+    pub fn start_playlist(&mut self) {
+        let time = Self::get_time();
+        self.playlist.start(time);
+        self.run_playlist(time);
+    }
+
+    pub fn pause_playlist(&mut self) {
+        self.playlist.pause(Self::get_time());
+    }
+
+    pub fn playlist_status(&self) -> PlaylistStatus {
+        self.playlist.status(Self::get_time())
+    }
+
+    // Shows the scene of the playlist, if a new step started.
+    fn run_playlist(&mut self, time: u128) {
+        match self.playlist.tick(time) {
+            Some((Scene::Icon(icon), _)) => self.show_icon(icon),
+            Some((Scene::Countdown, end)) => self.countdown_until(end.unwrap_or(time)),
+            Some((Scene::Function, _)) => self.set_state(HatState::Function),
+            None => {}
+        }
+    }
+
     pub fn start_countdown(&mut self, seconds: u128) {
-        self.countdown
-            .set_countdown(Self::get_time() + seconds * 1000);
+        self.countdown_until(Self::get_time() + seconds * 1000);
+    }
+
+    pub fn start_stopwatch(&mut self) {
+        self.countdown.start_stopwatch(Self::get_time());
+        self.set_state(HatState::Countdown);
+    }
+
+    pub fn pause_timer(&mut self) {
+        self.countdown.pause(Self::get_time());
+    }
+
+    pub fn resume_timer(&mut self) {
+        self.countdown.resume(Self::get_time());
+    }
+
+    pub fn add_time(&mut self, seconds: i64) {
+        self.countdown.add_time(seconds, Self::get_time());
+    }
+
+    pub fn set_countdown_phases(&mut self, phases: CountdownPhases) {
+        self.countdown.set_phases(phases);
+    }
+
+    /// Counts down to the given time, in ms since the epoch.
+    pub fn countdown_until(&mut self, end_ms: u128) {
+        self.countdown.set_countdown(end_ms);
         self.set_state(HatState::Countdown);
     }
 
+    pub fn show_text(&mut self, config: TextConfig) {
+        self.text.set_text(config, Self::get_time());
+        self.set_state(HatState::Text);
+    }
+
     pub fn show_icon(&mut self, icon: IconType) {
         self.icons.set_icon(icon);
         self.set_state(HatState::Icon);
     }
 
+    pub fn upload_icon(&mut self, upload: IconUpload) -> bool {
+        self.icons.upload(upload)
+    }
+
+    pub fn remove_icon(&mut self, name: &str) -> bool {
+        self.icons.remove(name)
+    }
+
+    pub fn icon_exists(&self, icon: &IconType) -> bool {
+        self.icons.exists(icon)
+    }
+
+    pub fn icon_names(&self) -> Vec<String> {
+        self.icons.names()
+    }
+
+    /// The LEDs as hex colours, for the simulation of the frontend.
+    pub fn leds_string(leds: &[LED]) -> String {
+        leds.iter()
+            .map(|led| led.to_string())
+            .collect::<Vec<_>>()
+            .join("")
+    }
+
+    /// The LEDs in the order of the strip.
+    pub fn leds_binary(geometry: &Geometry, leds: &[LED]) -> Vec<u8> {
+        geometry
+            .wire(leds)
+            .iter()
+            .flat_map(|led| vec![led.red(), led.green(), led.blue()])
+            .collect()
+    }
+
     pub fn get_time() -> u128 {
         std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
@@ -110,16 +354,24 @@ impl Switch {
             .as_millis()
     }
 
-    fn get_leds(&mut self) -> Vec<LED> {
+    /// The LEDs in the order of the layout.
+    pub fn get_leds(&mut self) -> Vec<LED> {
         let time = Self::get_time();
+        self.run_playlist(time);
 
-        match self.state {
+        let leds = match self.state {
             HatState::Function => {
-                self.function.check_formulas(time);
+                if let Some(compiled) = self.function.check_formulas(time) {
+                    self.history.played(compiled);
+                    self.transition.start(self.last_leds.clone(), time);
+                }
                 self.function.get_leds(time)
             }
             HatState::Icon => self.icons.get_leds(time),
             HatState::Countdown => self.countdown.get_leds(time),
-        }
+            HatState::Text => self.text.get_leds(time),
+        };
+        self.last_leds = self.transition.apply(leds, time);
+        self.last_leds.clone()
     }
 }
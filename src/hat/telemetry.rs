@@ -0,0 +1,181 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use serde::{Deserialize, Serialize};
+
+/// Values measured by a hat and sent with its requests.
+/// Values which are not sent are None.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Telemetry {
+    // ppm
+    pub co2: Option<u16>,
+    // Percent
+    pub battery: Option<u8>,
+    // Signal strength of the WiFi, in dBm
+    pub rssi: Option<i8>,
+    // Degrees Celsius
+    pub temperature: Option<f32>,
+    pub firmware: Option<String>,
+}
+
+impl Telemetry {
+    /// Takes the values of the other telemetry, and keeps the own ones the
+    /// other doesn't have.
+    pub fn merge(&mut self, other: Telemetry) {
+        self.co2 = other.co2.or(self.co2);
+        self.battery = other.battery.or(self.battery);
+        self.rssi = other.rssi.or(self.rssi);
+        self.temperature = other.temperature.or(self.temperature);
+        self.firmware = other.firmware.or(self.firmware.take());
+    }
+}
+
+/// Number of sequence numbers a request can be behind the last one and
+/// still count as late, rather than coming from a restarted hat.
+const LATE_MAX: u32 = 64;
+/// Time after which a hat which stopped sending requests is forgotten.
+const SOURCE_TIMEOUT_MS: u128 = 60_000;
+
+/// The last values reported by a hat, and how its requests arrive.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct DeviceTelemetry {
+    #[serde(flatten)]
+    pub values: Telemetry,
+    // Time of the last request, in ms since the epoch.
+    pub last_seen_ms: u128,
+    // The last sequence number received, if the hat sends them.
+    pub sequence: Option<u32>,
+    // Requests missing between the sequence numbers received, of all hats
+    // sending requests for this device.
+    pub lost: u64,
+    // The sequence numbers of every hat, by its address, as several hats
+    // can ask for the same device.
+    #[serde(skip)]
+    sources: HashMap<SocketAddr, Source>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Source {
+    sequence: u32,
+    lost: u64,
+    last_seen_ms: u128,
+}
+
+impl DeviceTelemetry {
+    /// Records a request of the hat, and returns whether its values were
+    /// taken.
+    /// Sequence numbers wrap around, and a request a little older than the
+    /// last one, which arrived late or twice, neither counts as lost nor
+    /// overwrites the newer values.
+    /// A request much older than the last one comes from a restarted hat,
+    /// which starts counting again.
+    pub fn update(
+        &mut self,
+        values: Telemetry,
+        sequence: Option<u32>,
+        source: SocketAddr,
+        time_ms: u128,
+    ) -> bool {
+        self.last_seen_ms = time_ms;
+        self.sources
+            .retain(|_, s| time_ms.saturating_sub(s.last_seen_ms) < SOURCE_TIMEOUT_MS);
+        if let Some(sequence) = sequence {
+            let restart = Source {
+                sequence,
+                lost: 0,
+                last_seen_ms: time_ms,
+            };
+            match self.sources.get_mut(&source) {
+                Some(last) => {
+                    let gap = sequence.wrapping_sub(last.sequence);
+                    if last.sequence.wrapping_sub(sequence) <= LATE_MAX {
+                        return false;
+                    }
+                    if gap < u32::MAX / 2 {
+                        last.lost += gap as u64 - 1;
+                        last.sequence = sequence;
+                        last.last_seen_ms = time_ms;
+                    } else {
+                        *last = restart;
+                    }
+                }
+                None => {
+                    self.sources.insert(source, restart);
+                }
+            }
+            self.sequence = Some(sequence);
+            self.lost = self.sources.values().map(|s| s.lost).sum();
+        }
+        self.values.merge(values);
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_telemetry() {
+        let mut device = DeviceTelemetry::default();
+        let hat: SocketAddr = "10.0.0.2:4000".parse().unwrap();
+        let co2 = |co2| Telemetry {
+            co2: Some(co2),
+            ..Telemetry::default()
+        };
+        assert!(device.update(
+            Telemetry {
+                battery: Some(80),
+                ..co2(400)
+            },
+            Some(u32::MAX - 1),
+            hat,
+            10,
+        ));
+        assert!(device.update(co2(500), Some(1), hat, 20));
+        assert_eq!(device.values.co2, Some(500));
+        assert_eq!(device.values.battery, Some(80));
+        assert_eq!((device.sequence, device.lost), (Some(1), 2));
+        assert_eq!(device.last_seen_ms, 20);
+
+        // Late and repeated requests
+        assert!(!device.update(co2(600), Some(0), hat, 30));
+        assert!(!device.update(co2(600), Some(1), hat, 40));
+        assert_eq!((device.sequence, device.lost), (Some(1), 2));
+        assert_eq!(device.values.co2, Some(500));
+        assert_eq!(device.last_seen_ms, 40);
+        assert!(device.update(co2(600), None, hat, 50));
+        assert_eq!(device.sequence, Some(1));
+        assert_eq!(device.values.co2, Some(600));
+    }
+
+    #[test]
+    fn test_telemetry_restart() {
+        let mut device = DeviceTelemetry::default();
+        let hat: SocketAddr = "10.0.0.2:4000".parse().unwrap();
+        let co2 = |co2| Telemetry {
+            co2: Some(co2),
+            ..Telemetry::default()
+        };
+        device.update(co2(400), Some(100_000), hat, 10);
+        device.update(co2(400), Some(100_005), hat, 20);
+        assert_eq!(device.lost, 4);
+
+        // The hat reboots
+        assert!(device.update(co2(450), Some(0), hat, 30));
+        assert!(device.update(co2(460), Some(1), hat, 40));
+        assert_eq!((device.sequence, device.lost), (Some(1), 0));
+        assert_eq!(device.values.co2, Some(460));
+
+        // Another hat for the same device counts on its own
+        let other: SocketAddr = "10.0.0.3:4000".parse().unwrap();
+        assert!(device.update(co2(470), Some(50_000), other, 50));
+        assert!(device.update(co2(480), Some(2), hat, 60));
+        assert!(device.update(co2(490), Some(50_002), other, 70));
+        assert_eq!(device.lost, 1);
+        assert_eq!(device.values.co2, Some(490));
+
+        // Hats which stopped are forgotten
+        assert!(device.update(co2(500), Some(3), hat, 70 + SOURCE_TIMEOUT_MS));
+        assert_eq!(device.lost, 0);
+    }
+}
@@ -0,0 +1,144 @@
+use serde::{Deserialize, Serialize};
+
+use crate::hat::{
+    font,
+    geometry::Geometry,
+    leds::{LEDCriss, LED},
+};
+
+/// Maximum number of characters of a message.
+pub const MESSAGE_MAX: usize = 200;
+/// Maximum speed of a message, in columns per second.
+pub const SPEED_MAX: f32 = 100.;
+
+/// A message scrolling around the hat.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TextConfig {
+    pub message: String,
+    // Columns per second, negative values scroll the other way.
+    #[serde(default = "TextConfig::default_speed")]
+    pub speed: f32,
+    #[serde(default = "TextConfig::default_color")]
+    pub color: LED,
+    #[serde(default = "LED::black")]
+    pub background: LED,
+}
+
+impl TextConfig {
+    fn default_speed() -> f32 {
+        10.
+    }
+
+    fn default_color() -> LED {
+        LED::from_rgb(0xa0, 0xa0, 0xa0)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.message.chars().count() <= MESSAGE_MAX
+            && self.speed.is_finite()
+            && self.speed.abs() <= SPEED_MAX
+    }
+}
+
+impl Default for TextConfig {
+    fn default() -> Self {
+        Self {
+            message: String::new(),
+            speed: Self::default_speed(),
+            color: Self::default_color(),
+            background: LED::black(),
+        }
+    }
+}
+
+pub struct Text {
+    leds: LEDCriss,
+    config: TextConfig,
+    // The message as columns of the font.
+    columns: Vec<u8>,
+    // Time the message was set, in ms, so it always starts at the same
+    // place.
+    time_start: u128,
+}
+
+impl Text {
+    pub fn new(geometry: Geometry) -> Self {
+        Self {
+            leds: LEDCriss::new(geometry),
+            config: TextConfig::default(),
+            columns: vec![],
+            time_start: 0,
+        }
+    }
+
+    pub fn set_text(&mut self, config: TextConfig, time_ms: u128) {
+        self.columns = font::columns(&config.message);
+        self.config = config;
+        self.time_start = time_ms;
+    }
+
+    pub fn get_text(&self) -> TextConfig {
+        self.config.clone()
+    }
+
+    pub fn time_start(&self) -> u128 {
+        self.time_start
+    }
+
+    pub fn get_leds(&mut self, time_ms: u128) -> Vec<LED> {
+        self.leds.fill(&self.config.background);
+        // A message longer than the circumference scrolls as a band,
+        // a shorter one goes around the hat.
+        let width = self.leds.range.0;
+        let period = self.columns.len().max(width) as i64;
+        let elapsed = time_ms.saturating_sub(self.time_start) as f32 / 1000.;
+        let offset = (elapsed * self.config.speed) as i64;
+        for (i, column) in self.columns.iter().enumerate() {
+            let x = (i as i64 + offset).rem_euclid(period) as usize;
+            if x < width {
+                self.leds.set_column(x, *column, self.config.color);
+            }
+        }
+        self.leds.leds.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lit(leds: &[LED]) -> Vec<usize> {
+        leds.iter()
+            .enumerate()
+            .filter(|(_, led)| led.red() > 0)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    #[test]
+    fn test_text() {
+        let mut text = Text::new(Geometry::new(300, 37));
+        text.set_text(
+            TextConfig {
+                message: "I".into(),
+                speed: 2.,
+                ..TextConfig::default()
+            },
+            1000,
+        );
+        let start = lit(&text.get_leds(1000));
+        assert!(!start.is_empty());
+        // After one second, the text moved by two columns, which is one LED.
+        let moved: Vec<_> = lit(&text.get_leds(2000));
+        assert_eq!(moved, start.iter().map(|i| i + 1).collect::<Vec<_>>());
+        // It comes back after going around the hat.
+        assert_eq!(lit(&text.get_leds(1000 + 73 * 500)), start);
+
+        assert!(TextConfig::default().is_valid());
+        let fast = TextConfig {
+            speed: 1000.,
+            ..TextConfig::default()
+        };
+        assert!(!fast.is_valid());
+    }
+}
@@ -0,0 +1,168 @@
+use serde::{Deserialize, Serialize};
+
+use std::sync::Arc;
+
+use crate::hat::{geometry::Geometry, layout::Layout, leds::LED};
+
+/// Longest transition the admin can configure, in ms.
+pub const DURATION_MAX: u128 = 10_000;
+
+/// How the LEDs go from one scene to the next.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransitionKind {
+    // Switches at once, like before transitions existed.
+    Cut,
+    // Blends all LEDs at the same time.
+    #[default]
+    Crossfade,
+    // Sweeps the new scene around the circumference of the hat.
+    Wipe,
+    // Switches the LEDs one by one, in a random-looking order.
+    Dissolve,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct TransitionConfig {
+    pub kind: TransitionKind,
+    pub duration_ms: u128,
+}
+
+impl Default for TransitionConfig {
+    fn default() -> Self {
+        Self {
+            kind: TransitionKind::Crossfade,
+            duration_ms: 500,
+        }
+    }
+}
+
+impl TransitionConfig {
+    pub fn is_valid(&self) -> bool {
+        self.duration_ms <= DURATION_MAX
+    }
+}
+
+/// Blends the LEDs shown before a scene change with the LEDs of the
+/// new scene, until the configured duration has passed.
+pub struct Transition {
+    layout: Arc<dyn Layout>,
+    config: TransitionConfig,
+    // The LEDs shown when the transition started, empty if there is
+    // no transition running.
+    from: Vec<LED>,
+    // Start of the transition, in ms
+    time_start: u128,
+}
+
+impl Transition {
+    pub fn new(geometry: Geometry) -> Self {
+        Self {
+            layout: geometry.layout(),
+            config: TransitionConfig::default(),
+            from: vec![],
+            time_start: 0,
+        }
+    }
+
+    pub fn config(&self) -> TransitionConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: TransitionConfig) {
+        self.config = config;
+    }
+
+    /// Starts a transition away from the given LEDs.
+    /// A transition already running starts over from the given LEDs, which
+    /// should be the LEDs shown last.
+    pub fn start(&mut self, from: Vec<LED>, time_ms: u128) {
+        self.from = from;
+        self.time_start = time_ms;
+    }
+
+    /// Returns the LEDs to show, given the LEDs of the new scene.
+    pub fn apply(&mut self, to: Vec<LED>, time_ms: u128) -> Vec<LED> {
+        let elapsed = time_ms.saturating_sub(self.time_start);
+        if self.config.kind == TransitionKind::Cut
+            || elapsed >= self.config.duration_ms
+            || self.from.len() != to.len()
+        {
+            self.from.clear();
+            return to;
+        }
+        let progress = elapsed as f32 / self.config.duration_ms as f32;
+
+        self.from
+            .iter()
+            .zip(to.iter())
+            .enumerate()
+            .map(|(i, (from, to))| match self.config.kind {
+                TransitionKind::Crossfade => from.mix(to, progress),
+                TransitionKind::Wipe if self.column(i) < progress => *to,
+                TransitionKind::Dissolve if Self::threshold(i) < progress => *to,
+                _ => *from,
+            })
+            .collect()
+    }
+
+    // Position of the LED around the hat, from 0 to 1.
+    fn column(&self, index: usize) -> f32 {
+        self.layout.position(index).0 as f32 / self.layout.size().0 as f32
+    }
+
+    // A fixed, but well spread, value between 0 and 1 for every LED.
+    fn threshold(index: usize) -> f32 {
+        ((index as u32).wrapping_mul(2_654_435_761) >> 16) as f32 / 65536.
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn transition(kind: TransitionKind) -> Transition {
+        let mut tr = Transition::new(Geometry::new(6, 3));
+        tr.set_config(TransitionConfig {
+            kind,
+            duration_ms: 100,
+        });
+        tr.start(vec![LED::black(); 10], 1000);
+        tr
+    }
+
+    // The number of LEDs which already show the new scene.
+    fn switched(leds: &[LED]) -> usize {
+        leds.iter().filter(|led| led.red() == 200).count()
+    }
+
+    #[test]
+    fn test_transition() {
+        let white = vec![LED::from_rgb(200, 200, 200); 10];
+
+        let mut tr = transition(TransitionKind::Crossfade);
+        let leds = tr.apply(white.clone(), 1050);
+        assert!(leds.iter().all(|led| led.red() == 100));
+        assert_eq!(switched(&tr.apply(white.clone(), 1100)), 10);
+        // Once done, the old LEDs are forgotten
+        assert_eq!(switched(&tr.apply(white.clone(), 1050)), 10);
+
+        let mut tr = transition(TransitionKind::Cut);
+        assert_eq!(switched(&tr.apply(white.clone(), 1000)), 10);
+
+        // With a circumference of 3, LEDs 0, 1, 2 are on columns 0, 2, 4.
+        let mut tr = transition(TransitionKind::Wipe);
+        let leds = tr.apply(white.clone(), 1050);
+        assert_eq!(switched(&leds[0..3]), 2);
+        assert_eq!(switched(&tr.apply(white.clone(), 1000)), 0);
+
+        let mut tr = transition(TransitionKind::Dissolve);
+        let half = switched(&tr.apply(white.clone(), 1050));
+        assert!(half > 0 && half < 10, "{half}");
+        assert_eq!(switched(&tr.apply(white.clone(), 1100)), 10);
+
+        // A different number of LEDs can't be blended
+        let mut tr = transition(TransitionKind::Crossfade);
+        assert_eq!(switched(&tr.apply(white[0..5].to_vec(), 1050)), 5);
+    }
+}
@@ -1,25 +1,108 @@
 use axum::{
-    extract::State,
-    http::StatusCode,
-    response::Json,
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts, Path, Query, State},
+    http::{request::Parts, HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
     routing::{get, post},
     Router,
 };
 use serde::{Deserialize, Serialize};
-use std::{env, sync::Arc};
-use tokio::{net::UdpSocket, sync::Mutex};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use tokio::net::UdpSocket;
 use tower_http::{services::ServeDir, trace::TraceLayer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use crate::hat::{function::FormulaStrings, icon::IconType, switch::HatStatus};
+use crate::config::Config;
+use crate::devices::{Devices, SharedHat};
+use crate::encoding::{PixelFormat, SentByHat};
+use crate::hat::{
+    countdown::{CountdownEnd, CountdownPhases},
+    function::{FormulaStrings, SubmitError},
+    history::HistoryPage,
+    icon::IconType,
+    leds::OutputMapping,
+    playlist::Playlist,
+    registry::IconUpload,
+    switch::{HatStatus, Switch},
+    text::TextConfig,
+    transition::TransitionConfig,
+};
 
+mod config;
+mod devices;
+mod encoding;
 mod hat;
+mod protocol;
 
+// Commands of the admin, either for the hat of the route or across the
+// devices, sent the same way, like `{"Icon": "Heart"}` or
+// `{"Mirror": "left"}`.
 #[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
 pub enum AdminCommand {
+    Devices(DevicesCommand),
+    Hat(HatCommand),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum DevicesCommand {
+    // Shows the LEDs of another device, or its own ones again with null.
+    Mirror(Option<String>),
+    // Shows the current scene on the given devices.
+    Sync(Vec<String>),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum HatCommand {
     Countdown(u128),
+    // Counts down to a wall-clock time, shown as Countdown in the status.
+    CountdownUntil(CountdownEnd),
+    CountdownPhases(CountdownPhases),
+    // Counts up from zero, shown as Stopwatch in the status.
+    Stopwatch,
+    // Pause, resume, and add seconds to the countdown or stopwatch.
+    // Negative seconds remove time.
+    PauseTimer,
+    ResumeTimer,
+    AddTime(i64),
     Icon(IconType),
+    // Adds or replaces an icon, shown by its name like the built-in ones.
+    UploadIcon(IconUpload),
+    RemoveIcon(String),
     AllowFunction,
+    // Scrolls a message around the hat.
+    Text(TextConfig),
+    OutputMapping(OutputMapping),
+    Transition(TransitionConfig),
+    // Replaces the playlist, which then waits for StartPlaylist.
+    UploadPlaylist(Playlist),
+    StartPlaylist,
+    PausePlaylist,
+    PlaylistStatus,
+    // Moderation of the formulas queue, all referring to the ids
+    // returned by ListQueue.
+    ListQueue,
+    RemoveFormula(u64),
+    MoveFormulaToFront(u64),
+    SkipFormula,
+    ClearQueue,
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "HistoryQuery::default_limit")]
+    limit: usize,
+}
+
+impl HistoryQuery {
+    fn default_limit() -> usize {
+        20
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -28,15 +111,58 @@ struct AdminRequest {
     command: AdminCommand,
 }
 
-type SharedHat = Arc<Mutex<hat::switch::Switch>>;
-
 #[derive(Clone)]
 struct AppState {
+    devices: Arc<Devices>,
+    admin_secret: String,
+    trusted_proxies: Arc<[IpAddr]>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DevicePath {
+    device: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReplayPath {
+    id: u64,
+}
+
+/// The hat named in the path, or the first device for the routes without
+/// a device.
+struct Hat {
+    id: String,
     hat: SharedHat,
 }
 
+#[async_trait]
+impl FromRequestParts<AppState> for Hat {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, StatusCode> {
+        let path = Option::<Path<DevicePath>>::from_request_parts(parts, state)
+            .await
+            .unwrap_or_default();
+        match path {
+            Some(Path(DevicePath { device })) => match state.devices.get(&device) {
+                Some(hat) => Ok(Hat { id: device, hat }),
+                None => Err(StatusCode::NOT_FOUND),
+            },
+            None => {
+                let (id, hat) = state.devices.first();
+                Ok(Hat { id: id.into(), hat })
+            }
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("led_hat: {e}");
+        std::process::exit(2);
+    });
+
     let file_appender = tracing_appender::rolling::daily("./logs", "led-hat.log");
     let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
     tracing_subscriber::registry()
@@ -48,60 +174,113 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer().with_writer(std::io::stdout))
         .init();
 
-    let shared_hat: SharedHat = Arc::new(Mutex::new(hat::switch::Switch::new(300, 37)));
-    {
-        shared_hat.lock().await.show_icon(IconType::Fosdem);
-        // shared_hat.lock().await.set_state(HatState::Function);
-        // shared_hat.lock().await.start_countdown(1000);
-    }
-
-    // Clone the hat for the UDP server
-    let udp_hat = shared_hat.clone();
+    let devices = Arc::new(Devices::new(&config.devices, config.queue_limit));
 
     // Spawn UDP server thread
+    let udp_devices = devices.clone();
     tokio::spawn(async move {
-        udp_server(udp_hat).await;
+        udp_server(udp_devices, config.udp).await;
     });
 
-    let app_state = AppState { hat: shared_hat };
+    let app_state = AppState {
+        devices,
+        admin_secret: config.admin_secret,
+        trusted_proxies: config.trusted_proxies.into(),
+    };
+
+    // The routes of every device, which are also the routes of the first
+    // device without its id.
+    let api = Router::new()
+        .route("/get_leds", get(get_leds))
+        .route("/get_icons", get(get_icons))
+        .route("/get_status", get(get_status))
+        .route("/set_formulas", post(set_formulas))
+        .route("/history", get(get_history))
+        .route("/replay/:id", post(replay))
+        .route("/admin", post(admin));
 
     let app = Router::new()
-        .route("/api/get_leds", get(get_leds))
-        .route("/api/get_icons", get(get_icons))
-        .route("/api/get_status", get(get_status))
-        .route("/api/set_formulas", post(set_formulas))
-        .route("/api/admin", post(admin))
+        .route("/api/get_devices", get(get_devices))
+        .nest("/api/:device", api.clone())
+        .nest("/api", api)
         .nest_service("/", ServeDir::new("html"))
         .layer(TraceLayer::new_for_http())
         .with_state(app_state);
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await.unwrap();
-    tracing::info!("Server running on http://0.0.0.0:8080");
+    let listener = tokio::net::TcpListener::bind(config.http).await.unwrap();
+    tracing::info!("Server running on http://{}", config.http);
 
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
 
-async fn udp_server(hat: SharedHat) {
-    use byteorder::{ByteOrder, LittleEndian};
-
-    let socket = UdpSocket::bind("0.0.0.0:8081").await.unwrap();
-    tracing::info!("UDP server listening on 0.0.0.0:8081");
+async fn udp_server(devices: Arc<Devices>, addr: SocketAddr) {
+    let socket = UdpSocket::bind(addr).await.unwrap();
+    tracing::info!("UDP server listening on {addr}");
 
     let mut buf = [0; 1024];
+    // The last frames sent to the hats, to encode deltas against.
+    let mut sent = SentByHat::new(devices.ids().len());
 
     loop {
         match socket.recv_from(&mut buf).await {
-            Ok((_, addr)) => {
-                // Get LED data in binary format
-                let led_data = {
-                    let mut hat_guard = hat.lock().await;
-                    let co2 = LittleEndian::read_u16(&buf);
-                    hat_guard.set_co2(co2);
-                    hat_guard.get_leds_binary()
+            Ok((len, addr)) => {
+                let request = match protocol::Request::parse(&buf[..len]) {
+                    Ok(request) => request,
+                    Err(e) => {
+                        tracing::warn!("Invalid UDP request from {addr}: {e}");
+                        continue;
+                    }
+                };
+                let device = match &request.device {
+                    None => Some(devices.first()),
+                    Some(id) => devices.get(id).map(|hat| (id.as_str(), hat)),
+                };
+                let Some((id, hat)) = device else {
+                    tracing::warn!("UDP request from {addr} for an unknown device");
+                    continue;
+                };
+                hat.lock()
+                    .await
+                    .report(request.telemetry, request.sequence, addr);
+                let time_ms = Switch::get_time();
+                let (geometry, leds) = devices.get_leds(&hat).await;
+                // Never fails, as the geometry limits the number of LEDs
+                let Ok(count) = u16::try_from(leds.len()) else {
+                    tracing::error!("Too many LEDs to send: {}", leds.len());
+                    continue;
+                };
+                let pixels = Switch::leds_binary(&geometry, &leds);
+                // Only hats with sequence numbers can acknowledge frames
+                let delta = request
+                    .sequence
+                    .filter(|_| request.formats & PixelFormat::Delta.bit() != 0);
+                let base = request.ack.filter(|_| delta.is_some()).and_then(|ack| {
+                    let frame = sent.get(id, addr)?.get(ack)?;
+                    Some((ack, frame))
+                });
+                let (format, encoded) = encoding::encode_best(request.formats, &pixels, base);
+                if let Some(sequence) = delta {
+                    sent.push(id, addr, sequence, pixels, time_ms);
+                }
+                let response = protocol::Response {
+                    sequence: request.sequence.unwrap_or_default(),
+                    time_ms: time_ms as u64,
+                    format,
+                    leds: count,
+                    pixels: encoded,
                 };
 
-                // Send the binary LED data back
-                if let Err(e) = socket.send_to(&led_data, addr).await {
+                // Send the LED data back, with a header if the request
+                // had one.
+                if let Err(e) = socket
+                    .send_to(&response.to_bytes(request.version), addr)
+                    .await
+                {
                     tracing::error!("Failed to send UDP response: {}", e);
                 }
             }
@@ -113,50 +292,238 @@ async fn udp_server(hat: SharedHat) {
     }
 }
 
-async fn get_leds(State(state): State<AppState>) -> String {
-    let mut hat = state.hat.lock().await;
-    hat.get_leds_string()
+async fn get_devices(State(state): State<AppState>) -> Json<Vec<String>> {
+    state.devices.ids().into()
+}
+
+async fn get_leds(State(state): State<AppState>, Hat { hat, .. }: Hat) -> String {
+    let (_, leds) = state.devices.get_leds(&hat).await;
+    Switch::leds_string(&leds)
 }
 
-async fn get_icons() -> String {
-    "Fosdem,Co2,Empty,Test,Pumpkin,Fish,Pacman,BlackAlps,TiTi".into()
+async fn get_icons(Hat { hat, .. }: Hat) -> String {
+    let hat = hat.lock().await;
+    hat.icon_names().join(",")
 }
 
-async fn get_status(State(state): State<AppState>) -> Json<HatStatus> {
-    let hat = state.hat.lock().await;
+async fn get_status(Hat { hat, .. }: Hat) -> Json<HatStatus> {
+    let hat = hat.lock().await;
     hat.get_status().into()
 }
 
+// Identifies who sent a formula by the address of the client.
+// Behind trusted proxies, it is the last address of X-Forwarded-For
+// which isn't one of them, as the addresses before it come from the
+// client and can be anything.
+fn submitter(headers: &HeaderMap, addr: SocketAddr, trusted_proxies: &[IpAddr]) -> String {
+    let mut client = addr.ip().to_canonical();
+    if trusted_proxies.contains(&client) {
+        let forwarded: Vec<IpAddr> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|ip| ip.trim().parse().ok())
+            .collect();
+        for ip in forwarded.into_iter().rev() {
+            client = ip;
+            if !trusted_proxies.contains(&ip) {
+                break;
+            }
+        }
+    }
+    format!("ip:{client}")
+}
+
+impl IntoResponse for SubmitError {
+    fn into_response(self) -> Response {
+        tracing::info!("Rejected formulas: {self}");
+        match self {
+            SubmitError::Invalid(e) => (StatusCode::UNPROCESSABLE_ENTITY, Json(e)).into_response(),
+            SubmitError::QueueFull { .. } => {
+                (StatusCode::TOO_MANY_REQUESTS, self.to_string()).into_response()
+            }
+        }
+    }
+}
+
 async fn set_formulas(
     State(state): State<AppState>,
+    Hat { hat, .. }: Hat,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(payload): Json<FormulaStrings>,
-) -> StatusCode {
-    tracing::info!("Got new formulas: {payload:?}");
-    let mut hat = state.hat.lock().await;
-    hat.add_formula(payload);
+) -> Result<StatusCode, SubmitError> {
+    let submitter = submitter(&headers, addr, &state.trusted_proxies);
+    tracing::info!("Got new formulas from {submitter}: {payload:?}");
+    let mut hat = hat.lock().await;
+    hat.add_formula(payload, submitter)?;
+
+    Ok(StatusCode::OK)
+}
 
-    StatusCode::OK
+async fn get_history(Hat { hat, .. }: Hat, Query(query): Query<HistoryQuery>) -> Json<HistoryPage> {
+    let hat = hat.lock().await;
+    hat.history().page(query.offset, query.limit).into()
 }
 
-async fn admin(State(state): State<AppState>, Json(payload): Json<AdminRequest>) -> StatusCode {
-    let admin_secret: String = env::var("LEDHAT_ADMIN")
-        .ok()
-        .unwrap_or_else(|| "".to_string());
+async fn replay(
+    State(state): State<AppState>,
+    Hat { hat, .. }: Hat,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(ReplayPath { id }): Path<ReplayPath>,
+) -> Result<StatusCode, SubmitError> {
+    let mut hat = hat.lock().await;
+    let Some(formula) = hat.history().get(id).map(|e| e.formula.clone()) else {
+        return Ok(StatusCode::NOT_FOUND);
+    };
+    tracing::info!("Replaying formula {id}");
+    hat.add_formula(formula, submitter(&headers, addr, &state.trusted_proxies))?;
+
+    Ok(StatusCode::OK)
+}
 
-    if admin_secret.is_empty() || payload.secret != admin_secret {
+async fn admin(
+    State(state): State<AppState>,
+    Hat { id, hat }: Hat,
+    Json(payload): Json<AdminRequest>,
+) -> Response {
+    if state.admin_secret.is_empty() || payload.secret != state.admin_secret {
         tracing::warn!("Admin access denied: invalid secret");
-        return StatusCode::UNAUTHORIZED;
+        return StatusCode::UNAUTHORIZED.into_response();
     }
 
-    tracing::info!("Admin command: {:?}", payload.command);
-
-    let mut hat = state.hat.lock().await;
+    tracing::info!("Admin command for {id}: {:?}", payload.command);
 
+    // The commands across devices lock them themselves, so they don't
+    // keep this one locked.
     match payload.command {
-        AdminCommand::Countdown(seconds) => hat.start_countdown(seconds),
-        AdminCommand::Icon(icon) => hat.show_icon(icon),
-        AdminCommand::AllowFunction => hat.allow_function(),
+        AdminCommand::Devices(DevicesCommand::Mirror(source)) => {
+            state.devices.mirror(&id, source).await.into_response()
+        }
+        AdminCommand::Devices(DevicesCommand::Sync(targets)) => {
+            state.devices.sync(&id, &targets).await.into_response()
+        }
+        AdminCommand::Hat(command) => hat_command(&mut *hat.lock().await, command),
     }
+}
 
-    StatusCode::OK
+fn hat_command(hat: &mut Switch, command: HatCommand) -> Response {
+    match command {
+        // Changing the scene by hand pauses the playlist.
+        HatCommand::Countdown(seconds) => {
+            hat.pause_playlist();
+            hat.start_countdown(seconds)
+        }
+        HatCommand::CountdownUntil(end) => {
+            let Some(end_ms) = end.to_ms().filter(|&ms| ms > Switch::get_time()) else {
+                tracing::warn!("Invalid or past countdown end: {end:?}");
+                return StatusCode::BAD_REQUEST.into_response();
+            };
+            hat.pause_playlist();
+            hat.countdown_until(end_ms)
+        }
+        HatCommand::Icon(icon) => {
+            if !hat.icon_exists(&icon) {
+                return found(false);
+            }
+            hat.pause_playlist();
+            hat.show_icon(icon)
+        }
+        HatCommand::UploadIcon(upload) => {
+            let name = upload.name.clone();
+            if !hat.upload_icon(upload) {
+                tracing::warn!("Invalid icon upload: {name}");
+                return StatusCode::BAD_REQUEST.into_response();
+            }
+        }
+        HatCommand::RemoveIcon(name) => return found(hat.remove_icon(&name)),
+        HatCommand::Stopwatch => {
+            hat.pause_playlist();
+            hat.start_stopwatch()
+        }
+        HatCommand::PauseTimer => hat.pause_timer(),
+        HatCommand::ResumeTimer => hat.resume_timer(),
+        HatCommand::AddTime(seconds) => hat.add_time(seconds),
+        HatCommand::CountdownPhases(phases) => {
+            if !phases.is_valid() {
+                tracing::warn!("Invalid countdown phases: {phases:?}");
+                return StatusCode::BAD_REQUEST.into_response();
+            }
+            hat.set_countdown_phases(phases)
+        }
+        HatCommand::AllowFunction => hat.allow_function(),
+        HatCommand::Text(config) => {
+            if !config.is_valid() {
+                tracing::warn!("Invalid text: {config:?}");
+                return StatusCode::BAD_REQUEST.into_response();
+            }
+            hat.pause_playlist();
+            hat.show_text(config)
+        }
+        HatCommand::OutputMapping(mapping) => {
+            if !mapping.is_valid() {
+                tracing::warn!("Invalid output mapping: {mapping:?}");
+                return StatusCode::BAD_REQUEST.into_response();
+            }
+            hat.set_output_mapping(mapping)
+        }
+        HatCommand::Transition(config) => {
+            if !config.is_valid() {
+                tracing::warn!("Invalid transition: {config:?}");
+                return StatusCode::BAD_REQUEST.into_response();
+            }
+            hat.set_transition(config)
+        }
+        HatCommand::UploadPlaylist(playlist) => {
+            if !playlist.is_valid() || !playlist.icons().all(|icon| hat.icon_exists(icon)) {
+                tracing::warn!("Invalid playlist: {playlist:?}");
+                return StatusCode::BAD_REQUEST.into_response();
+            }
+            hat.upload_playlist(playlist)
+        }
+        HatCommand::StartPlaylist => hat.start_playlist(),
+        HatCommand::PausePlaylist => hat.pause_playlist(),
+        HatCommand::PlaylistStatus => return Json(hat.playlist_status()).into_response(),
+        HatCommand::ListQueue => return Json(hat.list_queue()).into_response(),
+        HatCommand::RemoveFormula(id) => return found(hat.remove_formula(id)),
+        HatCommand::MoveFormulaToFront(id) => return found(hat.move_formula_to_front(id)),
+        HatCommand::SkipFormula => return found(hat.skip_formula()),
+        HatCommand::ClearQueue => hat.clear_queue(),
+    }
+
+    StatusCode::OK.into_response()
+}
+
+fn found(ok: bool) -> Response {
+    if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::NOT_FOUND
+    }
+    .into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_submitter() {
+        let addr: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "1.2.3.4, 5.6.7.8".parse().unwrap());
+        // Only trusted proxies forward addresses
+        assert_eq!(submitter(&headers, addr, &[]), "ip:10.0.0.1");
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        assert_eq!(submitter(&headers, addr, &[proxy]), "ip:5.6.7.8");
+        let inner: IpAddr = "5.6.7.8".parse().unwrap();
+        assert_eq!(submitter(&headers, addr, &[proxy, inner]), "ip:1.2.3.4");
+        let mapped: SocketAddr = "[::ffff:10.0.0.1]:1234".parse().unwrap();
+        assert_eq!(
+            submitter(&HeaderMap::new(), mapped, &[proxy]),
+            "ip:10.0.0.1"
+        );
+    }
 }
@@ -0,0 +1,358 @@
+use std::fmt;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::encoding::PixelFormat;
+use crate::hat::telemetry::Telemetry;
+
+/// Start of the requests and responses with a header, see the README for
+/// their fields.
+/// Requests without it are from older hats, which only send their CO2
+/// value and get the pixels without header.
+pub const MAGIC: [u8; 4] = *b"LHAT";
+/// Version 2 adds the encodings accepted by the hat, and the last frame
+/// it received, to base a delta on.
+pub const VERSION: u8 = 2;
+
+/// Bit of the formats telling that the ack is set, which it isn't before
+/// the hat received a response.
+pub const ACK_SET: u8 = 0x80;
+
+// Types of the telemetry values.
+const TLV_CO2: u8 = 1;
+const TLV_BATTERY: u8 = 2;
+const TLV_RSSI: u8 = 3;
+const TLV_TEMPERATURE: u8 = 4;
+const TLV_FIRMWARE: u8 = 5;
+
+/// A request of a hat for its LEDs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    // 0 for the requests without header.
+    pub version: u8,
+    // None for the first device.
+    pub device: Option<String>,
+    pub sequence: Option<u32>,
+    // Bit mask of the accepted pixel formats, RGB for older versions.
+    pub formats: u8,
+    // Sequence number of the last response the hat received.
+    pub ack: Option<u32>,
+    pub telemetry: Telemetry,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ProtocolError {
+    TooShort,
+    Version(u8),
+    DeviceId,
+    // A telemetry value of the given type with a wrong length.
+    Telemetry(u8),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::TooShort => write!(f, "message too short"),
+            ProtocolError::Version(v) => write!(f, "unsupported version {v}"),
+            ProtocolError::DeviceId => write!(f, "invalid device id"),
+            ProtocolError::Telemetry(t) => write!(f, "invalid telemetry of type {t}"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl Request {
+    pub fn parse(buf: &[u8]) -> Result<Self, ProtocolError> {
+        if !buf.starts_with(&MAGIC) {
+            return Self::parse_legacy(buf);
+        }
+        let version = match buf.get(4) {
+            Some(&version @ 1..=VERSION) => version,
+            Some(&version) => return Err(ProtocolError::Version(version)),
+            None => return Err(ProtocolError::TooShort),
+        };
+        // Sequence number, then the formats and ack of version 2
+        let header_len = if version == 1 { 9 } else { 14 };
+        let header = buf.get(5..header_len).ok_or(ProtocolError::TooShort)?;
+        let sequence = LittleEndian::read_u32(&header[..4]);
+        let (formats, ack) = match version {
+            1 => (PixelFormat::Rgb.bit(), None),
+            _ => (
+                header[4] & !ACK_SET,
+                Some(LittleEndian::read_u32(&header[5..9])).filter(|_| header[4] & ACK_SET != 0),
+            ),
+        };
+        let id_len = *buf.get(header_len).ok_or(ProtocolError::TooShort)? as usize;
+        let id_end = header_len + 1 + id_len;
+        let id = buf
+            .get(header_len + 1..id_end)
+            .ok_or(ProtocolError::TooShort)?;
+        Ok(Self {
+            version,
+            device: Self::device(id)?,
+            sequence: Some(sequence),
+            formats,
+            ack,
+            telemetry: Self::parse_telemetry(&buf[id_end..])?,
+        })
+    }
+
+    // The CO2 value, and the device id, if any.
+    fn parse_legacy(buf: &[u8]) -> Result<Self, ProtocolError> {
+        if buf.len() < 2 {
+            return Err(ProtocolError::TooShort);
+        }
+        Ok(Self {
+            version: 0,
+            device: Self::device(&buf[2..])?,
+            sequence: None,
+            formats: PixelFormat::Rgb.bit(),
+            ack: None,
+            telemetry: Telemetry {
+                co2: Some(LittleEndian::read_u16(buf)),
+                ..Telemetry::default()
+            },
+        })
+    }
+
+    fn device(id: &[u8]) -> Result<Option<String>, ProtocolError> {
+        match id {
+            [] => Ok(None),
+            id => String::from_utf8(id.to_vec())
+                .map(Some)
+                .map_err(|_| ProtocolError::DeviceId),
+        }
+    }
+
+    // Unknown types are skipped, so newer hats can send more values.
+    fn parse_telemetry(mut buf: &[u8]) -> Result<Telemetry, ProtocolError> {
+        let mut telemetry = Telemetry::default();
+        while let [kind, len, rest @ ..] = buf {
+            let value = rest.get(..*len as usize).ok_or(ProtocolError::TooShort)?;
+            let wrong = || ProtocolError::Telemetry(*kind);
+            match (*kind, value.len()) {
+                (TLV_CO2, 2) => telemetry.co2 = Some(LittleEndian::read_u16(value)),
+                (TLV_BATTERY, 1) => telemetry.battery = Some(value[0]),
+                (TLV_RSSI, 1) => telemetry.rssi = Some(value[0] as i8),
+                // Hundredths of degrees
+                (TLV_TEMPERATURE, 2) => {
+                    telemetry.temperature = Some(LittleEndian::read_i16(value) as f32 / 100.)
+                }
+                (TLV_FIRMWARE, _) => {
+                    telemetry.firmware =
+                        Some(String::from_utf8(value.to_vec()).map_err(|_| wrong())?)
+                }
+                (TLV_CO2..=TLV_TEMPERATURE, _) => return Err(wrong()),
+                _ => {}
+            }
+            buf = &rest[value.len()..];
+        }
+        match buf {
+            [] => Ok(telemetry),
+            _ => Err(ProtocolError::TooShort),
+        }
+    }
+}
+
+/// The LEDs sent back to a hat.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub sequence: u32,
+    pub time_ms: u64,
+    pub format: PixelFormat,
+    pub leds: u16,
+    pub pixels: Vec<u8>,
+}
+
+impl Response {
+    pub const HEADER_LEN: usize = 20;
+
+    /// The response in the version of the request, so older hats get the
+    /// pixels only.
+    pub fn to_bytes(&self, version: u8) -> Vec<u8> {
+        if version == 0 {
+            return self.pixels.clone();
+        }
+        let mut buf = Vec::with_capacity(Self::HEADER_LEN + self.pixels.len());
+        buf.extend_from_slice(&MAGIC);
+        buf.push(version);
+        buf.extend_from_slice(&self.sequence.to_le_bytes());
+        buf.extend_from_slice(&self.time_ms.to_le_bytes());
+        buf.push(self.format as u8);
+        buf.extend_from_slice(&self.leds.to_le_bytes());
+        buf.extend_from_slice(&self.pixels);
+        buf
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Builds the request like a hat.
+    fn encode(request: &Request) -> Vec<u8> {
+        let device = request.device.as_deref().unwrap_or_default().as_bytes();
+        let t = &request.telemetry;
+        let Some(sequence) = request.sequence.filter(|_| request.version > 0) else {
+            let mut buf = t.co2.unwrap_or_default().to_le_bytes().to_vec();
+            buf.extend_from_slice(device);
+            return buf;
+        };
+        let mut buf = MAGIC.to_vec();
+        buf.push(request.version);
+        buf.extend_from_slice(&sequence.to_le_bytes());
+        if request.version > 1 {
+            buf.push(request.formats | if request.ack.is_some() { ACK_SET } else { 0 });
+            buf.extend_from_slice(&request.ack.unwrap_or_default().to_le_bytes());
+        }
+        buf.push(device.len() as u8);
+        buf.extend_from_slice(device);
+        let mut tlv = |kind: u8, value: &[u8]| {
+            buf.extend_from_slice(&[kind, value.len() as u8]);
+            buf.extend_from_slice(value);
+        };
+        if let Some(co2) = t.co2 {
+            tlv(TLV_CO2, &co2.to_le_bytes());
+        }
+        if let Some(battery) = t.battery {
+            tlv(TLV_BATTERY, &[battery]);
+        }
+        if let Some(rssi) = t.rssi {
+            tlv(TLV_RSSI, &[rssi as u8]);
+        }
+        if let Some(temperature) = t.temperature {
+            tlv(
+                TLV_TEMPERATURE,
+                &((temperature * 100.).round() as i16).to_le_bytes(),
+            );
+        }
+        if let Some(firmware) = &t.firmware {
+            tlv(TLV_FIRMWARE, firmware.as_bytes());
+        }
+        buf
+    }
+
+    // Reads the response like a hat.
+    fn decode(buf: &[u8]) -> Response {
+        assert!(buf.starts_with(&MAGIC) && buf[4] == VERSION);
+        Response {
+            sequence: LittleEndian::read_u32(&buf[5..9]),
+            time_ms: LittleEndian::read_u64(&buf[9..17]),
+            format: PixelFormat::ALL[buf[17] as usize],
+            leds: LittleEndian::read_u16(&buf[18..20]),
+            pixels: buf[Response::HEADER_LEN..].to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_legacy() {
+        let request = Request::parse(&[0x90, 0x01]).unwrap();
+        assert_eq!(request.version, 0);
+        assert_eq!(request.telemetry.co2, Some(400));
+        assert_eq!(request.device, None);
+        let request = Request::parse(b"\x90\x01left").unwrap();
+        assert_eq!(request.device.as_deref(), Some("left"));
+        assert_eq!(Request::parse(&[1]), Err(ProtocolError::TooShort));
+        let request = Request {
+            device: None,
+            ..request
+        };
+        assert_eq!(Request::parse(&encode(&request)), Ok(request));
+
+        let response = Response {
+            sequence: 0,
+            time_ms: 0,
+            format: PixelFormat::Rgb,
+            leds: 1,
+            pixels: vec![1, 2, 3],
+        };
+        assert_eq!(response.to_bytes(0), [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_request() {
+        let request = Request {
+            version: VERSION,
+            device: Some("left".into()),
+            sequence: Some(7),
+            formats: PixelFormat::Rgb.bit() | PixelFormat::Delta.bit(),
+            ack: Some(6),
+            telemetry: Telemetry {
+                co2: Some(800),
+                battery: Some(55),
+                rssi: Some(-67),
+                temperature: Some(21.5),
+                firmware: Some("1.2.0".into()),
+            },
+        };
+        let bytes = encode(&request);
+        assert_eq!(
+            &bytes[..15],
+            b"LHAT\x02\x07\x00\x00\x00\x91\x06\x00\x00\x00\x04"
+        );
+        assert_eq!(Request::parse(&bytes), Ok(request.clone()));
+
+        // Version 1 accepts RGB only
+        let first = Request {
+            version: 1,
+            formats: PixelFormat::Rgb.bit(),
+            ack: None,
+            ..request.clone()
+        };
+        let first_bytes = encode(&first);
+        assert_eq!(&first_bytes[..10], b"LHAT\x01\x07\x00\x00\x00\x04");
+        assert_eq!(Request::parse(&first_bytes), Ok(first));
+
+        // The first request has no ack, even though its field is 0
+        let fresh = Request {
+            ack: None,
+            ..request.clone()
+        };
+        let fresh_bytes = encode(&fresh);
+        assert_eq!(&fresh_bytes[9..14], b"\x11\x00\x00\x00\x00");
+        assert_eq!(Request::parse(&fresh_bytes), Ok(fresh));
+
+        // Unknown types are skipped, wrong lengths rejected
+        let mut newer = bytes.clone();
+        newer.extend_from_slice(&[99, 2, 0, 0]);
+        assert_eq!(Request::parse(&newer), Ok(request));
+        let mut wrong = bytes[..19].to_vec();
+        wrong.extend_from_slice(&[TLV_BATTERY, 2, 0, 0]);
+        assert_eq!(
+            Request::parse(&wrong),
+            Err(ProtocolError::Telemetry(TLV_BATTERY))
+        );
+        assert_eq!(
+            Request::parse(&bytes[..bytes.len() - 1]),
+            Err(ProtocolError::TooShort)
+        );
+        assert_eq!(Request::parse(b"LHAT\x03"), Err(ProtocolError::Version(3)));
+        assert_eq!(
+            Request::parse(b"LHAT\x02\x07"),
+            Err(ProtocolError::TooShort)
+        );
+    }
+
+    #[test]
+    fn test_response() {
+        let response = Response {
+            sequence: 7,
+            time_ms: 1_767_225_600_000,
+            format: PixelFormat::Rgb,
+            leds: 2,
+            pixels: vec![1, 2, 3, 4, 5, 6],
+        };
+        let bytes = response.to_bytes(VERSION);
+        assert_eq!(bytes.len(), Response::HEADER_LEN + 6);
+        assert_eq!(decode(&bytes), response);
+        assert_eq!(response.to_bytes(1)[4], 1);
+
+        let rle = Response {
+            format: PixelFormat::Rle,
+            pixels: vec![2, 1, 2, 3],
+            ..response
+        };
+        assert_eq!(decode(&rle.to_bytes(VERSION)), rle);
+    }
+}